@@ -1,7 +1,21 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
-use super::hashgraph::NodeConfig;
+use super::hashgraph::{from_hex, MempoolConfig, NodeConfig};
+
+pub enum Command {
+  Run,
+  Keygen,
+  Dot,
+}
+
+pub struct Config {
+  pub node: NodeConfig,
+  pub command: Command,
+  pub key_path: Option<String>,
+  pub passphrase: Option<String>,
+}
 
 pub fn to_socket_addr(s: &str) -> SocketAddr {
   match s.parse::<SocketAddr>() {
@@ -12,7 +26,16 @@ pub fn to_socket_addr(s: &str) -> SocketAddr {
   }
 }
 
-pub fn parse_config() -> NodeConfig {
+pub fn to_key(s: &str) -> Vec<u8> {
+  match from_hex(s) {
+    Some(key) => key,
+    None => {
+      panic!("Invalid hex: {}", s);
+    }
+  }
+}
+
+pub fn parse_config() -> Config {
   let matches = App::new("Rust-Hashgraph")
     .version("1.0")
     .author("Champii <contact@champii.io>")
@@ -31,8 +54,35 @@ pub fn parse_config() -> NodeConfig {
         .long("connect")
         .value_name("IpAddr")
         .help("Connect to bootstrap node")
+        .global(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("connect-key")
+        .long("connect-key")
+        .value_name("PublicKey")
+        .help("Hex public key of the bootstrap node, as printed by keygen")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("http")
+        .long("http")
+        .value_name("IpAddr")
+        .help("Serve the JSON HTTP API on this address")
         .takes_value(true),
     )
+    .arg(
+      Arg::with_name("metrics")
+        .long("metrics")
+        .value_name("IpAddr")
+        .help("Serve the Prometheus metrics on this address")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("compact-gossip")
+        .long("compact-gossip")
+        .help("Gossip large transactions by hash, peers fetch the bodies they miss"),
+    )
     .arg(
       Arg::with_name("verbose")
         .short("v")
@@ -41,6 +91,83 @@ pub fn parse_config() -> NodeConfig {
         .help("Verbose level (between 0-5, default 2)")
         .takes_value(true),
     )
+    .arg(
+      Arg::with_name("stake")
+        .short("s")
+        .long("stake")
+        .value_name("Stake")
+        .help("Stake of this node if it bootstraps the network (default 1)")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("max-event-txs")
+        .long("max-event-txs")
+        .value_name("Count")
+        .help("Maximum transactions per event (default 256)")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("max-event-bytes")
+        .long("max-event-bytes")
+        .value_name("Bytes")
+        .help("Maximum transaction bytes per event (default 65536)")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("max-pool-txs")
+        .long("max-pool-txs")
+        .value_name("Count")
+        .help("Pending transactions before new ones are refused (default 10000)")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("data-dir")
+        .short("d")
+        .long("data-dir")
+        .value_name("Path")
+        .help("Directory of the event log, replayed on start (default: no persistence)")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("store-dir")
+        .long("store-dir")
+        .value_name("Path")
+        .help("Keep the events on disk instead of in memory")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("restore")
+        .long("restore")
+        .value_name("Path")
+        .help("Snapshot to start from, ignored if the data dir already holds a log")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("key")
+        .short("k")
+        .long("key")
+        .value_name("Path")
+        .help("PKCS#8 key file, created if missing (default: new key each run)")
+        .global(true)
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("passphrase")
+        .long("passphrase")
+        .value_name("Passphrase")
+        .env("HASHGRAPH_PASSPHRASE")
+        .help("Passphrase of an encrypted key file")
+        .global(true)
+        .takes_value(true),
+    )
+    .subcommand(
+      SubCommand::with_name("keygen")
+        .about("Generates a key, writes it to --key and prints the public key and peer id"),
+    )
+    .subcommand(
+      SubCommand::with_name("dot")
+        .about("Prints the hashgraph of the node at --connect in Graphviz DOT format"),
+    )
     .get_matches();
 
   let connect_addr_str = matches.value_of("connect").unwrap_or("");
@@ -59,9 +186,51 @@ pub fn parse_config() -> NodeConfig {
     .parse::<u8>()
     .unwrap();
 
-  NodeConfig {
-    listen_addr,
-    connect_addr,
-    verbose,
+  let stake = matches
+    .value_of("stake")
+    .unwrap_or("1")
+    .parse::<u64>()
+    .unwrap();
+
+  let default_mempool = MempoolConfig::default();
+
+  let mempool = MempoolConfig {
+    max_event_txs: matches
+      .value_of("max-event-txs")
+      .map_or(default_mempool.max_event_txs, |s| s.parse().unwrap()),
+    max_event_bytes: matches
+      .value_of("max-event-bytes")
+      .map_or(default_mempool.max_event_bytes, |s| s.parse().unwrap()),
+    max_pool_txs: matches
+      .value_of("max-pool-txs")
+      .map_or(default_mempool.max_pool_txs, |s| s.parse().unwrap()),
+    ..default_mempool
+  };
+
+  let command = match matches.subcommand_name() {
+    Some("keygen") => Command::Keygen,
+    Some("dot") => Command::Dot,
+    _ => Command::Run,
+  };
+
+  Config {
+    node: NodeConfig {
+      listen_addr,
+      connect_addr,
+      connect_key: matches.value_of("connect-key").map(to_key),
+      verbose,
+      stake,
+      data_dir: matches.value_of("data-dir").map(PathBuf::from),
+      restore: matches.value_of("restore").map(PathBuf::from),
+      store_dir: matches.value_of("store-dir").map(PathBuf::from),
+      mempool,
+      http_addr: matches.value_of("http").map(to_socket_addr),
+      metrics_addr: matches.value_of("metrics").map(to_socket_addr),
+      compact_gossip: matches.is_present("compact-gossip"),
+      ..NodeConfig::default()
+    },
+    command,
+    key_path: matches.value_of("key").map(|s| s.to_string()),
+    passphrase: matches.value_of("passphrase").map(|s| s.to_string()),
   }
 }
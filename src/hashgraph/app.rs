@@ -0,0 +1,38 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::transaction::ConsensusTransaction;
+
+// State machine driven by the node
+pub trait Application: Send {
+    // called before a submitted transaction enters an event
+    fn check_tx(&mut self, _tx: &[u8]) -> bool {
+        true
+    }
+
+    // called for every transaction, in consensus order
+    fn deliver_tx(&mut self, tx: &ConsensusTransaction);
+
+    // called once all the transactions received in the round were delivered,
+    // returns the hash of the resulting state
+    fn commit(&mut self, round: u64) -> Vec<u8>;
+
+    fn snapshot(&self) -> Vec<u8>;
+
+    fn restore(&mut self, snapshot: &[u8]);
+}
+
+#[derive(Clone)]
+pub struct AppHandle(pub Arc<Mutex<Box<Application>>>);
+
+impl AppHandle {
+    pub fn new<A: Application + 'static>(app: A) -> AppHandle {
+        AppHandle(Arc::new(Mutex::new(Box::new(app))))
+    }
+}
+
+impl fmt::Debug for AppHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AppHandle")
+    }
+}
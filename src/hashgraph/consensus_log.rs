@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::transaction::ConsensusTransaction;
+
+pub const DEFAULT_CONSENSUS_LOG_SIZE: usize = 10_000;
+
+#[derive(Debug)]
+struct LogState {
+    capacity: usize,
+    txs: VecDeque<ConsensusTransaction>,
+    next_index: u64,
+}
+
+// The last ordered transactions, shared between the hashgraph that appends
+// them and the readers that wait for new ones. Only the last `capacity` are
+// kept; with a data dir, the event log replay rebuilds them with the same
+// indices after a restart.
+#[derive(Clone, Debug)]
+pub struct ConsensusLog {
+    state: Arc<(Mutex<LogState>, Condvar)>,
+}
+
+impl Default for ConsensusLog {
+    fn default() -> ConsensusLog {
+        ConsensusLog::new(DEFAULT_CONSENSUS_LOG_SIZE)
+    }
+}
+
+impl ConsensusLog {
+    pub fn new(capacity: usize) -> ConsensusLog {
+        ConsensusLog {
+            state: Arc::new((
+                Mutex::new(LogState {
+                    capacity,
+                    txs: VecDeque::new(),
+                    next_index: 0,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    pub fn push(&self, tx: ConsensusTransaction) {
+        let &(ref lock, ref cvar) = &*self.state;
+
+        let mut state = lock.lock().unwrap();
+
+        state.next_index = tx.index + 1;
+        state.txs.push_back(tx);
+
+        while state.txs.len() > state.capacity {
+            state.txs.pop_front();
+        }
+
+        cvar.notify_all();
+    }
+
+    // forgets the retained transactions, the next one pushed will have
+    // `next_index`. Used when the state is restored from a snapshot
+    pub fn reset(&self, next_index: u64) {
+        let mut state = self.state.0.lock().unwrap();
+
+        state.txs.clear();
+        state.next_index = next_index;
+    }
+
+    // index of the oldest transaction still retained
+    pub fn first_index(&self) -> Option<u64> {
+        let state = self.state.0.lock().unwrap();
+
+        state.txs.front().map(|tx| tx.index)
+    }
+
+    pub fn next_index(&self) -> u64 {
+        self.state.0.lock().unwrap().next_index
+    }
+
+    // an independent cursor starting at `from`
+    pub fn subscribe(&self, from: u64) -> Subscription {
+        Subscription {
+            log: self.clone(),
+            next: from,
+        }
+    }
+
+    // at most `max` transactions from index `from`, waiting up to `timeout`
+    // for the first one. Err(first retained index) if `from` was dropped.
+    pub fn read_from(
+        &self,
+        from: u64,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<ConsensusTransaction>, u64> {
+        let &(ref lock, ref cvar) = &*self.state;
+
+        let mut state = lock.lock().unwrap();
+
+        if from >= state.next_index {
+            state = cvar.wait_timeout(state, timeout).unwrap().0;
+
+            if from >= state.next_index {
+                return Ok(vec![]);
+            }
+        }
+
+        let first = state.txs.front().map_or(state.next_index, |tx| tx.index);
+
+        if from < first {
+            return Err(first);
+        }
+
+        Ok(state
+            .txs
+            .iter()
+            .skip((from - first) as usize)
+            .take(max)
+            .cloned()
+            .collect())
+    }
+}
+
+// Reads the ordered transactions one by one, without gaps nor duplicates.
+// A consumer that restarts subscribes again from its last index + 1.
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    log: ConsensusLog,
+    next: u64,
+}
+
+impl Subscription {
+    // index of the next transaction to be received
+    pub fn cursor(&self) -> u64 {
+        self.next
+    }
+
+    // None on timeout. Err(first retained index) if the subscriber fell
+    // behind the bounded log: transactions were missed and it must resync.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<ConsensusTransaction>, u64> {
+        let mut txs = self.log.read_from(self.next, 1, timeout)?;
+
+        Ok(txs.pop().map(|tx| {
+            self.next = tx.index + 1;
+
+            tx
+        }))
+    }
+
+    pub fn recv(&mut self) -> Result<ConsensusTransaction, u64> {
+        loop {
+            if let Some(tx) = self.recv_timeout(Duration::from_secs(1))? {
+                return Ok(tx);
+            }
+        }
+    }
+}
+
+// ends when the subscriber fell behind
+impl Iterator for Subscription {
+    type Item = ConsensusTransaction;
+
+    fn next(&mut self) -> Option<ConsensusTransaction> {
+        self.recv().ok()
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+use super::event::{Event, EventCreator, EventHash};
+use super::event_store::EventStore;
+use super::events::Events;
+use super::hex::to_hex;
+use super::round::{FamousType, Round};
+
+// Graphviz rendering of the retained events, one column per creator and a
+// box per round inside each column. Witnesses have a double border and are
+// coloured by fame: green famous, red not famous, yellow undecided.
+// Self-parent edges are solid, other-parent edges dashed.
+pub fn render<S: EventStore>(events: &Events<S>, rounds: &BTreeMap<u64, Round>) -> String {
+    let all_events = events.all_events();
+
+    // creator -> round -> events, in chain order
+    let mut columns: BTreeMap<EventCreator, BTreeMap<u64, Vec<&Event>>> = BTreeMap::new();
+
+    for event in &all_events {
+        columns
+            .entry(event.creator)
+            .or_insert_with(BTreeMap::new)
+            .entry(event.round)
+            .or_insert_with(Vec::new)
+            .push(event);
+    }
+
+    let known = all_events
+        .iter()
+        .map(|event| event.hash)
+        .collect::<HashSet<EventHash>>();
+
+    let mut out = String::new();
+
+    out.push_str("digraph hashgraph {\n");
+    out.push_str("  rankdir=BT;\n");
+    out.push_str("  node [shape=box, style=filled, fillcolor=white, fontsize=10];\n");
+
+    for (creator, by_round) in &columns {
+        writeln!(out, "  subgraph cluster_{} {{", creator).unwrap();
+        writeln!(out, "    label=\"creator {}\";", creator).unwrap();
+
+        for (round_id, round_events) in by_round {
+            writeln!(out, "    subgraph cluster_{}_{} {{", creator, round_id).unwrap();
+            writeln!(out, "      label=\"round {}\";", round_id).unwrap();
+            writeln!(out, "      style=dashed;").unwrap();
+
+            for event in round_events {
+                writeln!(out, "      {};", node(event, events, rounds)).unwrap();
+            }
+
+            writeln!(out, "    }}").unwrap();
+        }
+
+        writeln!(out, "  }}").unwrap();
+    }
+
+    for event in &all_events {
+        // parents of purged or missing events are not drawn
+        if known.contains(&event.self_parent) {
+            writeln!(
+                out,
+                "  \"{}\" -> \"{}\";",
+                to_hex(&event.hash),
+                to_hex(&event.self_parent)
+            )
+            .unwrap();
+        }
+
+        if known.contains(&event.other_parent) {
+            writeln!(
+                out,
+                "  \"{}\" -> \"{}\" [style=dashed, color=blue];",
+                to_hex(&event.hash),
+                to_hex(&event.other_parent)
+            )
+            .unwrap();
+        }
+    }
+
+    out.push_str("}\n");
+
+    out
+}
+
+fn node<S: EventStore>(event: &Event, events: &Events<S>, rounds: &BTreeMap<u64, Round>) -> String {
+    let mut label = format!(
+        "{}\\nid {} r {}",
+        &to_hex(&event.hash)[..8],
+        event.id,
+        event.round
+    );
+
+    let mut attrs = vec![];
+
+    // purged rounds forgot their events
+    let round_event = rounds
+        .get(&event.round)
+        .and_then(|round| round.events.get(&event.hash));
+
+    if let Some(round_event) = round_event {
+        if round_event.witness {
+            attrs.push("peripheries=2".to_string());
+
+            let color = match round_event.famous {
+                FamousType::True => "palegreen",
+                FamousType::False => "lightcoral",
+                FamousType::Undefined => "khaki",
+            };
+
+            attrs.push(format!("fillcolor={}", color));
+        }
+
+        // received and timestamp are only meaningful once decided
+        if !events.undecided.contains_key(&event.hash) {
+            label.push_str(&format!(
+                "\\nreceived {}\\nts {}",
+                round_event.received, round_event.timestamp
+            ));
+        }
+    }
+
+    attrs.insert(0, format!("label=\"{}\"", label));
+
+    format!("\"{}\" [{}]", to_hex(&event.hash), attrs.join(", "))
+}
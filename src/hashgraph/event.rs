@@ -1,11 +1,17 @@
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use bincode;
+use ring::digest;
+
+use super::fork::ForkProof;
 use super::internal_txs::PeerTx;
+use super::key::Key;
 
 pub type EventCreator = u64;
-pub type EventHash = u64;
+pub type EventHash = [u8; 32];
+
+// parent hash of root events
+pub const EMPTY_HASH: EventHash = [0; 32];
 
 #[derive(Hash, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Event {
@@ -18,6 +24,10 @@ pub struct Event {
     pub round: u64,
     pub transactions: Vec<Vec<u8>>,
     pub internal_transactions: Vec<PeerTx>,
+    // forks this creator learnt of since its last event, its descendants no
+    // longer count the forkers when strongly seeing
+    pub fork_proofs: Vec<ForkProof>,
+    pub signature: Vec<u8>,
 }
 
 impl Event {
@@ -31,7 +41,7 @@ impl Event {
     ) -> Event {
         let mut ev = Event {
             id,
-            hash: 0,
+            hash: EMPTY_HASH,
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
@@ -42,26 +52,58 @@ impl Event {
             round: 0,
             transactions,
             internal_transactions,
+            fork_proofs: vec![],
+            signature: vec![],
         };
 
-        ev.calc_hash();
+        ev.hash = ev.calc_hash();
 
         ev
     }
 
-    pub fn calc_hash(&mut self) {
-        if self.hash != 0 {
-            return;
-        }
+    // SHA-256 of the canonical body, parents included
+    pub fn calc_hash(&self) -> EventHash {
+        let mut hash = EMPTY_HASH;
 
-        let mut hasher = DefaultHasher::new();
+        hash.copy_from_slice(digest::digest(&digest::SHA256, &self.body_bytes()).as_ref());
 
-        self.hash(&mut hasher);
+        hash
+    }
+
+    pub fn check_hash(&self) -> bool {
+        self.hash == self.calc_hash()
+    }
+
+    // Canonical encoding of everything but the hash, the signature and the
+    // locally computed round. Bincode's default config writes fixed width
+    // little-endian integers and u64 length prefixes, so this is stable
+    // across platforms and compiler versions.
+    pub fn body_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(
+            self.id,
+            self.timestamp,
+            self.creator,
+            self.self_parent,
+            self.other_parent,
+            &self.transactions,
+            &self.internal_transactions,
+            self.fork_proofs
+                .iter()
+                .map(|proof| (proof.first.hash, proof.second.hash))
+                .collect::<Vec<(EventHash, EventHash)>>(),
+        ))
+        .unwrap()
+    }
+
+    pub fn sign(&mut self, key: &Key) {
+        self.signature = key.sign(&self.hash);
+    }
 
-        self.hash = hasher.finish();
+    pub fn verify(&self, pub_key: &[u8]) -> bool {
+        Key::verify(pub_key.to_vec(), self.signature.clone(), self.hash.to_vec())
     }
 
     pub fn is_root(&self) -> bool {
-        self.self_parent == 0
+        self.self_parent == EMPTY_HASH
     }
 }
@@ -0,0 +1,204 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use bincode;
+use ring::digest;
+
+use super::event::Event;
+use super::events::Frame;
+use super::fork::ForkProof;
+use super::peers::Peers;
+use super::snapshot::Snapshot;
+
+const CHECKSUM_LEN: usize = 8;
+const HEADER_LEN: usize = 4 + CHECKSUM_LEN;
+const COMPACT_MIN_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LogEntry {
+    Bootstrap(Peers),
+    Frame(Frame, u64), // frame, self_id
+    Event(Event),
+    Snapshot(Snapshot),
+    ForkProof(ForkProof), // detected here, not carried by an event yet
+}
+
+// Append-only log of everything needed to rebuild a Hashgraph, synced to
+// the disk on every append. Once it has grown past COMPACT_MIN_BYTES and
+// doubled since the last compaction, the Hashgraph replaces it with a
+// snapshot, see Hashgraph::compact_log.
+// Each record is: payload length (u32 LE) | SHA-256(payload)[..8] | payload
+#[derive(Debug)]
+pub struct EventLog {
+    path: PathBuf,
+    file: File,
+    bytes: u64,
+    compacted_bytes: u64,
+}
+
+impl EventLog {
+    pub fn open(data_dir: &Path) -> Result<EventLog, String> {
+        fs::create_dir_all(data_dir)
+            .map_err(|e| format!("Cannot create data dir {:?}: {}", data_dir, e))?;
+
+        let path = data_dir.join("events.log");
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| format!("Cannot open event log {:?}: {}", path, e))?;
+
+        let bytes = file
+            .metadata()
+            .map_err(|e| format!("Cannot open event log {:?}: {}", path, e))?
+            .len();
+
+        Ok(EventLog {
+            path,
+            file,
+            bytes,
+            compacted_bytes: 0,
+        })
+    }
+
+    // Reads every complete record. A torn or corrupted tail, left by a crash
+    // in the middle of an append, is truncated away.
+    pub fn read_all(&mut self) -> Result<Vec<LogEntry>, String> {
+        let mut content = vec![];
+
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.read_to_end(&mut content))
+            .map_err(|e| format!("Cannot read event log {:?}: {}", self.path, e))?;
+
+        let mut entries = vec![];
+        let mut offset = 0;
+
+        while offset < content.len() {
+            match Self::read_record(&content[offset..]) {
+                Some((entry, len)) => {
+                    entries.push(entry);
+
+                    offset += len;
+                }
+                None => {
+                    warn!(
+                        "Event log: Truncating {} bytes of torn tail at {}",
+                        content.len() - offset,
+                        offset
+                    );
+
+                    self.file
+                        .set_len(offset as u64)
+                        .map_err(|e| format!("Cannot truncate event log: {}", e))?;
+
+                    self.bytes = offset as u64;
+
+                    break;
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_record(buf: &[u8]) -> Option<(LogEntry, usize)> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+
+        let len = match bincode::deserialize::<u32>(&buf[..4]) {
+            Ok(len) => len as usize,
+            Err(_) => return None,
+        };
+
+        if buf.len() < HEADER_LEN + len {
+            return None;
+        }
+
+        let payload = &buf[HEADER_LEN..HEADER_LEN + len];
+
+        if Self::checksum(payload)[..] != buf[4..HEADER_LEN] {
+            return None;
+        }
+
+        bincode::deserialize(payload)
+            .ok()
+            .map(|entry| (entry, HEADER_LEN + len))
+    }
+
+    fn checksum(payload: &[u8]) -> Vec<u8> {
+        digest::digest(&digest::SHA256, payload).as_ref()[..CHECKSUM_LEN].to_vec()
+    }
+
+    fn record(entry: &LogEntry) -> Vec<u8> {
+        let payload = bincode::serialize(entry).unwrap();
+
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+
+        record.extend(bincode::serialize(&(payload.len() as u32)).unwrap());
+        record.extend(Self::checksum(&payload));
+        record.extend(payload);
+
+        record
+    }
+
+    // durable once it returns
+    pub fn append(&mut self, entry: &LogEntry) -> Result<(), String> {
+        let record = Self::record(entry);
+
+        // a single write, so that a crash leaves at most one torn record
+        self.file
+            .write_all(&record)
+            .map_err(|e| format!("Cannot append to event log: {}", e))?;
+
+        self.bytes += record.len() as u64;
+
+        self.file
+            .sync_data()
+            .map_err(|e| format!("Cannot sync event log: {}", e))
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.bytes > COMPACT_MIN_BYTES && self.bytes > 2 * self.compacted_bytes
+    }
+
+    // replaces the whole log with `entries`. They are written to another file
+    // renamed over the log, a crash leaves either one whole
+    pub fn compact(&mut self, entries: &[LogEntry]) -> Result<(), String> {
+        let tmp_path = self.path.with_extension("log.tmp");
+
+        let content = entries.iter().fold(vec![], |mut content, entry| {
+            content.extend(Self::record(entry));
+
+            content
+        });
+
+        File::create(&tmp_path)
+            .and_then(|mut tmp| tmp.write_all(&content).and_then(|_| tmp.sync_data()))
+            .map_err(|e| format!("Cannot write {:?}: {}", tmp_path, e))?;
+
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| format!("Cannot replace event log {:?}: {}", self.path, e))?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Cannot open event log {:?}: {}", self.path, e))?;
+
+        info!(
+            "Event log: Compacted {} bytes to {}",
+            self.bytes,
+            content.len()
+        );
+
+        self.bytes = content.len() as u64;
+        self.compacted_bytes = self.bytes;
+
+        Ok(())
+    }
+}
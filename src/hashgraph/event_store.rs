@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bincode;
+
+use super::event::{Event, EventCreator, EventHash};
+
+// Storage of the known events, indexed by hash and by creator chain.
+// Implementations only store, all the checks are done by Events
+pub trait EventStore: Debug + Send + Sync {
+    // the event is not stored on error
+    fn insert(&mut self, event: Event) -> Result<(), String>;
+
+    fn get(&self, hash: &EventHash) -> Option<Event>;
+
+    fn get_by_id(&self, creator: EventCreator, id: u64) -> Option<Event>;
+
+    fn last_of(&self, creator: EventCreator) -> Option<Event>;
+
+    fn creators(&self) -> Vec<EventCreator>;
+
+    // events of a creator with an id in [from, to], ordered by id
+    fn range(&self, creator: EventCreator, from: u64, to: u64) -> Vec<Event>;
+
+    fn purge(&mut self, hashes: &[EventHash]);
+}
+
+impl EventStore for Box<EventStore> {
+    fn insert(&mut self, event: Event) -> Result<(), String> {
+        (**self).insert(event)
+    }
+
+    fn get(&self, hash: &EventHash) -> Option<Event> {
+        (**self).get(hash)
+    }
+
+    fn get_by_id(&self, creator: EventCreator, id: u64) -> Option<Event> {
+        (**self).get_by_id(creator, id)
+    }
+
+    fn last_of(&self, creator: EventCreator) -> Option<Event> {
+        (**self).last_of(creator)
+    }
+
+    fn creators(&self) -> Vec<EventCreator> {
+        (**self).creators()
+    }
+
+    fn range(&self, creator: EventCreator, from: u64, to: u64) -> Vec<Event> {
+        (**self).range(creator, from, to)
+    }
+
+    fn purge(&mut self, hashes: &[EventHash]) {
+        (**self).purge(hashes)
+    }
+}
+
+impl Default for Box<EventStore> {
+    fn default() -> Box<EventStore> {
+        Box::new(MemEventStore::new())
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct MemEventStore {
+    by_hash: HashMap<EventHash, Event>,
+    by_creator: HashMap<EventCreator, BTreeMap<u64, Event>>, // creator -> (id, event)
+}
+
+impl MemEventStore {
+    pub fn new() -> MemEventStore {
+        MemEventStore {
+            by_hash: HashMap::new(),
+            by_creator: HashMap::new(),
+        }
+    }
+}
+
+impl EventStore for MemEventStore {
+    fn insert(&mut self, event: Event) -> Result<(), String> {
+        self.by_hash.insert(event.hash, event.clone());
+
+        self.by_creator
+            .entry(event.creator)
+            .or_insert_with(BTreeMap::new)
+            .insert(event.id, event);
+
+        Ok(())
+    }
+
+    fn get(&self, hash: &EventHash) -> Option<Event> {
+        self.by_hash.get(hash).map(|event| event.clone())
+    }
+
+    fn get_by_id(&self, creator: EventCreator, id: u64) -> Option<Event> {
+        self.by_creator
+            .get(&creator)
+            .and_then(|events| events.get(&id))
+            .map(|event| event.clone())
+    }
+
+    fn last_of(&self, creator: EventCreator) -> Option<Event> {
+        self.by_creator
+            .get(&creator)
+            .and_then(|events| events.values().last())
+            .map(|event| event.clone())
+    }
+
+    fn creators(&self) -> Vec<EventCreator> {
+        self.by_creator.keys().cloned().collect()
+    }
+
+    fn range(&self, creator: EventCreator, from: u64, to: u64) -> Vec<Event> {
+        match self.by_creator.get(&creator) {
+            Some(events) if from <= to => events.range(from..=to).map(|(_, e)| e.clone()).collect(),
+            _ => vec![],
+        }
+    }
+
+    fn purge(&mut self, hashes: &[EventHash]) {
+        for hash in hashes {
+            if let Some(event) = self.by_hash.remove(hash) {
+                let empty = match self.by_creator.get_mut(&event.creator) {
+                    Some(events) => {
+                        events.remove(&event.id);
+
+                        events.len() == 0
+                    }
+                    None => false,
+                };
+
+                if empty {
+                    self.by_creator.remove(&event.creator);
+                }
+            }
+        }
+    }
+}
+
+// Keeps only the indices in memory, the events themselves are appended to a
+// file and read back on demand. The file is a cache that starts empty on
+// each open: durability is the job of the EventLog. Purged events are
+// unindexed, and the file is rewritten with the live ones once the purged
+// bytes make more than half of it.
+#[derive(Clone, Debug)]
+pub struct FileEventStore {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+    file_bytes: u64,
+    purged_bytes: u64,
+    // hash -> (offset, len, creator, id)
+    by_hash: HashMap<EventHash, (u64, u64, EventCreator, u64)>,
+    by_creator: HashMap<EventCreator, BTreeMap<u64, EventHash>>, // creator -> (id, hash)
+}
+
+impl FileEventStore {
+    pub fn open(dir: &Path) -> Result<FileEventStore, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Cannot create store dir {:?}: {}", dir, e))?;
+
+        let path = dir.join("events.db");
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(|e| format!("Cannot open event store {:?}: {}", path, e))?;
+
+        Ok(FileEventStore {
+            path,
+            file: Arc::new(Mutex::new(file)),
+            file_bytes: 0,
+            purged_bytes: 0,
+            by_hash: HashMap::new(),
+            by_creator: HashMap::new(),
+        })
+    }
+
+    fn read(&self, hash: &EventHash) -> Option<Event> {
+        let (offset, len) = match self.by_hash.get(hash) {
+            Some(&(offset, len, _, _)) => (offset, len),
+            None => return None,
+        };
+
+        let mut buf = vec![0; len as usize];
+
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(e) = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut buf))
+        {
+            error!("Event store: Cannot read event: {}", e);
+
+            return None;
+        }
+
+        bincode::deserialize(&buf).ok()
+    }
+
+    // writes the live events to a new file that replaces the current one.
+    // Clones keep reading the old file, still open but unlinked
+    fn compact(&mut self) -> Result<(), String> {
+        let tmp_path = self.path.with_extension("db.tmp");
+
+        let mut tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .map_err(|e| format!("Cannot open {:?}: {}", tmp_path, e))?;
+
+        let mut offsets = HashMap::new();
+        let mut offset = 0;
+
+        {
+            let mut file = self.file.lock().unwrap();
+
+            for (hash, &(old_offset, len, _, _)) in self.by_hash.iter() {
+                let mut buf = vec![0; len as usize];
+
+                file.seek(SeekFrom::Start(old_offset))
+                    .and_then(|_| file.read_exact(&mut buf))
+                    .map_err(|e| format!("Cannot read event: {}", e))?;
+
+                tmp.write_all(&buf)
+                    .map_err(|e| format!("Cannot write event: {}", e))?;
+
+                offsets.insert(*hash, offset);
+
+                offset += len;
+            }
+        }
+
+        fs::rename(&tmp_path, &self.path)
+            .map_err(|e| format!("Cannot replace {:?}: {}", self.path, e))?;
+
+        for (hash, entry) in self.by_hash.iter_mut() {
+            entry.0 = offsets[hash];
+        }
+
+        trace!(
+            "Event store: Compacted {} bytes to {}",
+            self.file_bytes,
+            offset
+        );
+
+        self.file = Arc::new(Mutex::new(tmp));
+        self.file_bytes = offset;
+        self.purged_bytes = 0;
+
+        Ok(())
+    }
+}
+
+impl EventStore for FileEventStore {
+    fn insert(&mut self, event: Event) -> Result<(), String> {
+        let bytes = bincode::serialize(&event).unwrap();
+
+        let offset = {
+            let mut file = self.file.lock().unwrap();
+
+            file.seek(SeekFrom::End(0))
+                .and_then(|offset| file.write_all(&bytes).map(|_| offset))
+                .map_err(|e| format!("Cannot write event: {}", e))?
+        };
+
+        self.file_bytes = offset + bytes.len() as u64;
+
+        self.by_hash.insert(
+            event.hash,
+            (offset, bytes.len() as u64, event.creator, event.id),
+        );
+
+        self.by_creator
+            .entry(event.creator)
+            .or_insert_with(BTreeMap::new)
+            .insert(event.id, event.hash);
+
+        Ok(())
+    }
+
+    fn get(&self, hash: &EventHash) -> Option<Event> {
+        self.read(hash)
+    }
+
+    fn get_by_id(&self, creator: EventCreator, id: u64) -> Option<Event> {
+        self.by_creator
+            .get(&creator)
+            .and_then(|events| events.get(&id))
+            .and_then(|hash| self.read(hash))
+    }
+
+    fn last_of(&self, creator: EventCreator) -> Option<Event> {
+        self.by_creator
+            .get(&creator)
+            .and_then(|events| events.values().last())
+            .and_then(|hash| self.read(hash))
+    }
+
+    fn creators(&self) -> Vec<EventCreator> {
+        self.by_creator.keys().cloned().collect()
+    }
+
+    fn range(&self, creator: EventCreator, from: u64, to: u64) -> Vec<Event> {
+        match self.by_creator.get(&creator) {
+            Some(events) if from <= to => events
+                .range(from..=to)
+                .filter_map(|(_, hash)| self.read(hash))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    fn purge(&mut self, hashes: &[EventHash]) {
+        for hash in hashes {
+            let (creator, id) = match self.by_hash.remove(hash) {
+                Some((_, len, creator, id)) => {
+                    self.purged_bytes += len;
+
+                    (creator, id)
+                }
+                None => continue,
+            };
+
+            let empty = match self.by_creator.get_mut(&creator) {
+                Some(events) => {
+                    events.remove(&id);
+
+                    events.len() == 0
+                }
+                None => false,
+            };
+
+            if empty {
+                self.by_creator.remove(&creator);
+            }
+        }
+
+        if self.purged_bytes * 2 > self.file_bytes {
+            if let Err(e) = self.compact() {
+                error!("Event store: Cannot compact: {}", e);
+            }
+        }
+    }
+}
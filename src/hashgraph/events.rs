@@ -1,19 +1,80 @@
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
-use super::event::{Event, EventCreator, EventHash};
+use bincode;
+
+use super::event::{Event, EventCreator, EventHash, EMPTY_HASH};
+use event_store::{EventStore, MemEventStore};
+use key::Key;
+use metrics::{self, Registry};
 use peers::Peers;
+use receipt::{tx_id, TxId};
+
+// Events and transaction bytes of one diff, pull page or push. A diff above
+// them is refused whole, an honest sender leaves the rest for the next one
+pub const MAX_DIFF_EVENTS: u64 = 1024;
+pub const MAX_DIFF_BYTES: usize = 16 * 1024 * 1024;
+
+// Transactions at least this big are left out of compact diffs
+pub const LAZY_TX_BYTES: usize = 1024;
+
+// bounds of one answer to fetch_txs
+pub const MAX_FETCH_TXS: usize = 1024;
+pub const MAX_FETCH_BYTES: usize = 16 * 1024 * 1024;
+
+// A transaction left out of a compact diff: the body of the transaction at
+// `index` in the event `event`
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxRef {
+    pub event: EventHash,
+    pub index: usize,
+    pub id: TxId,
+}
 
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
 pub struct Frame {
     pub events: BTreeMap<u64, (Peers, HashMap<EventCreator, BTreeMap<u64, Event>>)>, // round_id -> (peers, (creator_id -> (event_id, event)))
+    pub sender_id: EventCreator,
+    pub signature: Vec<u8>,
 }
 
 impl Frame {
     pub fn new() -> Frame {
         Frame {
             events: BTreeMap::new(),
+            sender_id: 0,
+            signature: vec![],
         }
     }
+
+    // the events are covered through their hashes, which are checked on insertion
+    fn signed_bytes(&self) -> Vec<u8> {
+        let rounds = self
+            .events
+            .iter()
+            .map(|(round_id, (peers, creators))| {
+                (
+                    round_id.clone(),
+                    bincode::serialize(peers).unwrap(),
+                    sorted_hashes(creators),
+                )
+            })
+            .collect::<Vec<(u64, Vec<u8>, BTreeMap<EventCreator, Vec<(u64, EventHash)>>)>>();
+
+        bincode::serialize(&(self.sender_id, rounds)).unwrap()
+    }
+
+    pub fn sign(&mut self, key: &Key) {
+        self.signature = key.sign(&self.signed_bytes());
+    }
+
+    pub fn verify(&self, pub_key: &[u8]) -> bool {
+        Key::verify(
+            pub_key.to_vec(),
+            self.signature.clone(),
+            self.signed_bytes(),
+        )
+    }
 }
 
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
@@ -21,78 +82,285 @@ pub struct EventsDiff {
     pub known: HashMap<EventCreator, u64>,
     pub diff: HashMap<EventCreator, BTreeMap<u64, Event>>, // creator -> (id, event),
     pub sender_id: EventCreator,
+    // the node a push is for, 0 for the answer to a pull
+    pub recipient_id: EventCreator,
+    // increases with each diff of the sender, a push is taken once
+    pub counter: u64,
     pub has_more: bool,
-    // todo: add signature
+    // bodies left out, their events carry an empty transaction instead. Not
+    // signed, the bodies are checked against the event hashes
+    pub lazy: Vec<TxRef>,
+    pub signature: Vec<u8>,
 }
 
-#[derive(Clone, Default, Serialize, Deserialize, Debug)]
-pub struct Events {
-    by_hash: HashMap<EventHash, Event>,
-    by_creator: HashMap<EventCreator, BTreeMap<u64, Event>>, // creator -> (id, event)
+impl EventsDiff {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let known = self
+            .known
+            .iter()
+            .map(|(creator, id)| (creator.clone(), id.clone()))
+            .collect::<BTreeMap<EventCreator, u64>>();
+
+        bincode::serialize(&(
+            self.sender_id,
+            self.recipient_id,
+            self.counter,
+            self.has_more,
+            known,
+            sorted_hashes(&self.diff),
+        ))
+        .unwrap()
+    }
+
+    pub fn sign(&mut self, key: &Key) {
+        self.signature = key.sign(&self.signed_bytes());
+    }
+
+    pub fn verify(&self, pub_key: &[u8]) -> bool {
+        Key::verify(
+            pub_key.to_vec(),
+            self.signature.clone(),
+            self.signed_bytes(),
+        )
+    }
+
+    // what a node knowing `known` knows once it has this diff
+    pub fn known_after(&self, known: HashMap<EventCreator, u64>) -> HashMap<EventCreator, u64> {
+        let mut known = known;
+
+        for (creator, events) in self.diff.iter() {
+            if let Some(last) = events.keys().last() {
+                let id = known.entry(*creator).or_insert(*last);
+
+                *id = max(*id, *last);
+            }
+        }
+
+        known
+    }
+
+    // events and transaction bytes carried
+    pub fn size(&self) -> (u64, usize) {
+        self.diff
+            .values()
+            .flat_map(|events| events.values())
+            .fold((0, 0), |(count, bytes), event| {
+                (count + 1, bytes + event_bytes(event))
+            })
+    }
+
+    // leaves out the bodies of `min_bytes` or more, for the receiver to
+    // find them on its side or fetch them. The signature stays valid
+    pub fn strip_txs(&mut self, min_bytes: usize) {
+        for events in self.diff.values_mut() {
+            for event in events.values_mut() {
+                for (index, tx) in event.transactions.iter_mut().enumerate() {
+                    if tx.len() < min_bytes {
+                        continue;
+                    }
+
+                    self.lazy.push(TxRef {
+                        event: event.hash,
+                        index,
+                        id: tx_id(tx),
+                    });
+
+                    *tx = vec![];
+                }
+            }
+        }
+    }
+
+    // puts back left out bodies, ignoring the ones not announced or not
+    // matching their id. Returns how many were
+    pub fn fill_txs(&mut self, bodies: Vec<(TxRef, Vec<u8>)>) -> usize {
+        let lazy = self.lazy.iter().cloned().collect::<HashSet<TxRef>>();
+
+        // event hash -> its bodies, to reach each event once
+        let mut by_event: HashMap<EventHash, Vec<(TxRef, Vec<u8>)>> = HashMap::new();
+
+        for (tx_ref, body) in bodies {
+            if lazy.contains(&tx_ref) && tx_id(&body) == tx_ref.id {
+                by_event
+                    .entry(tx_ref.event)
+                    .or_insert_with(Vec::new)
+                    .push((tx_ref, body));
+            }
+        }
+
+        let mut filled = HashSet::new();
+
+        for events in self.diff.values_mut() {
+            for event in events.values_mut() {
+                for (tx_ref, body) in by_event.remove(&event.hash).unwrap_or_default() {
+                    if tx_ref.index < event.transactions.len() {
+                        event.transactions[tx_ref.index] = body;
+
+                        filled.insert(tx_ref);
+                    }
+                }
+            }
+        }
+
+        self.lazy.retain(|tx_ref| !filled.contains(tx_ref));
+
+        filled.len()
+    }
+
+    // removes the events still missing a body and returns how many. Their
+    // descendants stay, they wait for a later pull like any orphan
+    pub fn drop_incomplete(&mut self) -> usize {
+        let incomplete = self
+            .lazy
+            .iter()
+            .map(|tx_ref| tx_ref.event)
+            .collect::<HashSet<EventHash>>();
+
+        let mut dropped = 0;
+
+        for events in self.diff.values_mut() {
+            let ids = events
+                .values()
+                .filter(|event| incomplete.contains(&event.hash))
+                .map(|event| event.id)
+                .collect::<Vec<u64>>();
+
+            for id in ids {
+                events.remove(&id);
+
+                dropped += 1;
+            }
+        }
+
+        self.lazy.clear();
+
+        dropped
+    }
+}
+
+fn event_bytes(event: &Event) -> usize {
+    event.transactions.iter().map(|tx| tx.len()).sum()
+}
+
+// HashMap iteration order differs between nodes, the signed content must not
+fn sorted_hashes(
+    events: &HashMap<EventCreator, BTreeMap<u64, Event>>,
+) -> BTreeMap<EventCreator, Vec<(u64, EventHash)>> {
+    events
+        .iter()
+        .map(|(creator, events)| {
+            (
+                creator.clone(),
+                events
+                    .iter()
+                    .map(|(id, event)| (id.clone(), event.hash))
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Events<S: EventStore = MemEventStore> {
+    store: S,
+    // kept in memory whatever the store, as consensus works on them
     pub undecided: HashMap<EventHash, Event>,
+    pub metrics: Registry,
+}
+
+impl Events<MemEventStore> {
+    pub fn new() -> Events<MemEventStore> {
+        Events::with_store(MemEventStore::new())
+    }
 }
 
-impl Events {
-    pub fn new() -> Events {
+impl<S: EventStore> Events<S> {
+    pub fn with_store(store: S) -> Events<S> {
         Events {
-            by_hash: HashMap::new(),
-            by_creator: HashMap::new(),
+            store,
             undecided: HashMap::new(),
+            metrics: Registry::new(),
         }
     }
 
-    pub fn insert_event(&mut self, event: Event) {
-        self.by_hash.insert(event.hash, event.clone());
-        self.undecided.insert(event.hash, event.clone());
-
-        self.by_creator
-            .entry(event.creator)
-            .and_modify(|events| {
-                events.insert(event.id, event.clone());
-            })
-            .or_insert_with(|| {
-                let mut res = BTreeMap::new();
+    pub fn insert_event(&mut self, event: Event) -> Result<(), String> {
+        self.store.insert(event.clone())?;
 
-                res.insert(event.id, event.clone());
-
-                res
-            });
+        self.undecided.insert(event.hash, event.clone());
 
         trace!("Insert Event {:?}", event);
+
+        Ok(())
     }
 
-    pub fn check_event(&self, event: &Event) -> bool {
-        if let Some(_) = self.by_hash.get(&event.hash) {
+    pub fn check_event(&self, event: &Event, peers: &Peers) -> bool {
+        if self.get_event(&event.hash).is_some() {
             debug!("Insert event: Known event: {:?}", event);
 
+            metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "known")]);
+
             return false;
         }
 
-        let creator_events = self.by_creator.get(&event.creator);
+        if !event.check_hash() {
+            error!("Insert event: Bad hash: {:?}", event);
+
+            metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "bad_hash")]);
+
+            return false;
+        }
+
+        match peers.clone().get_by_id(event.creator) {
+            Some(peer) => {
+                if !event.verify(&peer.pub_key) {
+                    error!("Insert event: Bad signature: {:?}", event);
+
+                    metrics::EVENTS_REJECTED
+                        .inc_with(&self.metrics, &[("reason", "bad_signature")]);
+
+                    return false;
+                }
+            }
+            None => {
+                debug!("Insert event: Unknown creator: {:?}", event);
+
+                metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "unknown_creator")]);
 
-        if let Some(events) = creator_events {
-            if events.len() > 0 && events.values().last().unwrap().id + 1 != event.id {
+                return false;
+            }
+        }
+
+        let last_creator_event = self.get_last_event_of(event.creator);
+
+        if let Some(ref last) = last_creator_event {
+            if last.id + 1 != event.id {
                 error!("Insert event: Non-sequential event: {:?}", event);
 
+                metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "non_sequential")]);
+
                 return false;
             }
         }
 
-        match self.by_hash.get(&event.self_parent) {
+        match self.get_event(&event.self_parent) {
             Some(e) => {
                 if e.creator != event.creator {
                     error!("Insert event: Bad event self-parent {:?}", event);
 
+                    metrics::EVENTS_REJECTED
+                        .inc_with(&self.metrics, &[("reason", "bad_self_parent")]);
+
                     return false;
                 }
             }
             None => {
-                if let Some(arr) = creator_events {
-                    if arr.len() > 0 {
-                        error!("Insert event: Bad event self-parent is nil {:?}", event);
+                if last_creator_event.is_some() {
+                    error!("Insert event: Bad event self-parent is nil {:?}", event);
 
-                        return false;
-                    }
+                    metrics::EVENTS_REJECTED
+                        .inc_with(&self.metrics, &[("reason", "bad_self_parent")]);
+
+                    return false;
                 }
             }
         }
@@ -100,11 +368,35 @@ impl Events {
         true
     }
 
+    // a known event of the same creator at the same position of its chain
+    pub fn find_fork(&self, event: &Event) -> Option<Event> {
+        let is_fork = |e: &Event| e.hash != event.hash;
+
+        if let Some(known) = self.store.get_by_id(event.creator, event.id) {
+            if is_fork(&known) {
+                return Some(known);
+            }
+        }
+
+        if event.is_root() || event.id == 0 {
+            return None;
+        }
+
+        // the known child of the same self-parent
+        self.store
+            .get(&event.self_parent)
+            .filter(|parent| parent.creator == event.creator)
+            .and_then(|parent| self.store.get_by_id(event.creator, parent.id + 1))
+            .filter(|e| e.self_parent == event.self_parent && is_fork(e))
+    }
+
     pub fn known_events(&self) -> HashMap<EventCreator, u64> {
         let mut res = HashMap::new();
 
-        for (peer_id, events) in &self.by_creator {
-            res.insert(peer_id.clone(), events.values().last().unwrap().id);
+        for peer_id in self.store.creators() {
+            if let Some(last) = self.store.last_of(peer_id) {
+                res.insert(peer_id, last.id);
+            }
         }
 
         trace!("Known {:?}", res);
@@ -112,57 +404,89 @@ impl Events {
         res
     }
 
+    // The events the other node misses, only the first `limit` of them (0 for
+    // no other limit than MAX_DIFF_EVENTS and MAX_DIFF_BYTES), parents first,
+    // so the page can be inserted as is; `has_more` then tells to pull again
+    // with the updated known events.
     pub fn events_diff(&self, other_known: HashMap<EventCreator, u64>, limit: u64) -> EventsDiff {
-        let mut res_events = HashMap::new();
         let known = self.known_events();
         let mut has_more = false;
 
+        let limit = if limit == 0 || limit > MAX_DIFF_EVENTS {
+            MAX_DIFF_EVENTS
+        } else {
+            limit
+        };
+
         trace!("EventsDiff: Other Known {:?}", other_known);
 
+        // the next missing events of each creator, at most `limit` of them
+        let mut queues = BTreeMap::new();
+
         for (peer_id, last_known) in &known {
-            match other_known.get(peer_id) {
-                Some(other_last_known) => {
-                    let mut other_last_known = other_last_known.clone();
-                    let mut last_known = last_known.clone();
+            let from = other_known.get(peer_id).map_or(0, |id| id + 1);
 
-                    if other_last_known < last_known.clone() {
-                        if limit > 0 && other_last_known + limit < last_known {
-                            warn!("LIMIT !!!!, {}", last_known - other_last_known);
-                            has_more = true;
-                            last_known = other_last_known + limit;
-                        }
+            if from > *last_known {
+                continue;
+            }
 
-                        let events = self.by_creator.get(peer_id).unwrap();
-                        let mut to_add = BTreeMap::new();
+            let mut to = *last_known;
 
-                        for i in other_last_known.clone()..=last_known.clone() {
-                            let mut event = events.get(&i);
+            if to - from >= limit {
+                has_more = true;
+                to = from + limit - 1;
+            }
 
-                            if event.is_none() {
-                                continue;
-                            }
+            let events = self.store.range(*peer_id, from, to);
 
-                            let mut event = event.unwrap();
+            queues.insert(*peer_id, events.into_iter().collect::<VecDeque<Event>>());
+        }
 
-                            to_add.insert(event.id, event.clone());
-                        }
+        let mut res_events: HashMap<EventCreator, BTreeMap<u64, Event>> = HashMap::new();
+        let mut sent = HashSet::new();
+        let mut bytes = 0;
 
-                        res_events.insert(peer_id.clone(), to_add);
-                    }
-                }
-                None => {
-                    let mut events = self.by_creator.get(peer_id).unwrap().clone();
+        loop {
+            if sent.len() as u64 >= limit {
+                break;
+            }
 
-                    // if limit > 0 && events.len() > limit as usize {
-                    //     events.truncate(limit as usize);
+            // the self-parent of a head is known or sent, only the
+            // other-parent can be missing
+            let ready = queues
+                .iter()
+                .find(|(_, queue)| match queue.front() {
+                    Some(event) => self.known_by(&event.other_parent, &other_known, &sent),
+                    None => false,
+                })
+                .map(|(peer_id, _)| *peer_id);
 
-                    //     has_more = true;
-                    // }
+            let peer_id = match ready {
+                Some(peer_id) => peer_id,
+                None => break,
+            };
 
-                    // res_known.insert(peer_id.clone(), events.len() as u64);
-                    res_events.insert(peer_id.clone(), events.clone());
-                }
+            let event = queues.get_mut(&peer_id).unwrap().pop_front().unwrap();
+
+            bytes += event_bytes(&event);
+
+            // an event alone above the limit still goes, the receiver decides
+            if bytes > MAX_DIFF_BYTES && !sent.is_empty() {
+                queues.get_mut(&peer_id).unwrap().push_front(event);
+
+                break;
             }
+
+            sent.insert(event.hash);
+
+            res_events
+                .entry(peer_id)
+                .or_insert_with(BTreeMap::new)
+                .insert(event.id, event);
+        }
+
+        if queues.values().any(|queue| !queue.is_empty()) {
+            has_more = true;
         }
 
         trace!(
@@ -172,43 +496,88 @@ impl Events {
 
         EventsDiff {
             sender_id: 0,
+            recipient_id: 0,
+            counter: 0,
             known: known,
             diff: res_events,
             has_more,
+            lazy: vec![],
+            signature: vec![],
+        }
+    }
+
+    // whether the other node has this event or is about to receive it. An
+    // event unknown here, or purged, cannot be sent anyway
+    fn known_by(
+        &self,
+        hash: &EventHash,
+        other_known: &HashMap<EventCreator, u64>,
+        sent: &HashSet<EventHash>,
+    ) -> bool {
+        if *hash == EMPTY_HASH || sent.contains(hash) {
+            return true;
+        }
+
+        match self.get_event(hash) {
+            Some(event) => other_known
+                .get(&event.creator)
+                .map_or(false, |id| event.id <= *id),
+            None => true,
         }
     }
 
     pub fn get_last_event_of(&self, creator: EventCreator) -> Option<Event> {
-        self.by_creator
-            .get(&creator)
-            .map(|events| events.values().last().unwrap().clone())
+        self.store.last_of(creator)
     }
 
+    // undecided events are served from memory, the store only on a miss
     pub fn get_event(&self, hash: &EventHash) -> Option<Event> {
-        self.by_hash.get(hash).map(|event| event.clone())
+        self.undecided
+            .get(hash)
+            .map(|event| event.clone())
+            .or_else(|| self.store.get(hash))
     }
 
-    pub fn purge(&mut self, events: Vec<EventHash>) {
-        let events = events
+    // every stored event, each creator chain in order
+    pub fn all_events(&self) -> Vec<Event> {
+        self.store
+            .creators()
+            .into_iter()
+            .flat_map(|creator| self.store.range(creator, 0, ::std::u64::MAX))
+            .collect()
+    }
+
+    // replaces the whole content of the store
+    pub fn restore(&mut self, events: Vec<Event>, undecided: Vec<EventHash>) {
+        let stored = self
+            .all_events()
             .iter()
-            .map(|hash| self.by_hash.get(hash).unwrap().clone())
-            .collect::<Vec<Event>>();
+            .map(|event| event.hash)
+            .collect::<Vec<EventHash>>();
 
-        for event in events.clone() {
-            self.by_creator
-                .get_mut(&event.creator)
-                .unwrap()
-                .remove(&event.id);
+        self.store.purge(&stored);
 
-            self.by_creator = self
-                .by_creator
-                .iter()
-                .filter(|(_, events)| events.len() > 0)
-                .map(|(id, events)| (id.clone(), events.clone()))
-                .collect();
+        self.undecided.clear();
 
-            self.by_hash.remove(&event.hash);
+        let undecided = undecided.into_iter().collect::<HashSet<EventHash>>();
+
+        for event in events {
+            let hash = event.hash;
+
+            if let Err(e) = self.store.insert(event.clone()) {
+                error!("Events: Cannot restore {:?}: {}", hash, e);
+
+                continue;
+            }
+
+            if undecided.contains(&hash) {
+                self.undecided.insert(hash, event);
+            }
         }
+    }
+
+    pub fn purge(&mut self, events: Vec<EventHash>) {
+        self.store.purge(&events);
 
         trace!("Events: Purged {}", events.len());
     }
@@ -0,0 +1,42 @@
+use super::event::{Event, EventCreator};
+use super::peers::Peers;
+
+// Two events signed by the same creator at the same position of its
+// self-parent chain. Anyone holding the creator's public key can check it.
+#[derive(Hash, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ForkProof {
+    pub first: Event,
+    pub second: Event,
+}
+
+impl ForkProof {
+    pub fn new(first: Event, second: Event) -> ForkProof {
+        ForkProof { first, second }
+    }
+
+    pub fn creator(&self) -> EventCreator {
+        self.first.creator
+    }
+
+    pub fn verify(&self, peers: &Peers) -> bool {
+        let first = &self.first;
+        let second = &self.second;
+
+        if first.creator != second.creator || first.hash == second.hash {
+            return false;
+        }
+
+        if first.id != second.id && first.self_parent != second.self_parent {
+            return false;
+        }
+
+        if !first.check_hash() || !second.check_hash() {
+            return false;
+        }
+
+        match peers.clone().get_by_id(first.creator) {
+            Some(peer) => first.verify(&peer.pub_key) && second.verify(&peer.pub_key),
+            None => false,
+        }
+    }
+}
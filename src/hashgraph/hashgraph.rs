@@ -1,24 +1,84 @@
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
-
-use super::event::{Event, EventCreator, EventHash};
-use super::events::{Events, EventsDiff, Frame};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::app::AppHandle;
+use super::consensus_log::ConsensusLog;
+use super::dot;
+use super::event::{Event, EventCreator, EventHash, EMPTY_HASH};
+use super::event_log::{EventLog, LogEntry};
+use super::event_store::{EventStore, MemEventStore};
+use super::events::{
+    Events, EventsDiff, Frame, TxRef, LAZY_TX_BYTES, MAX_DIFF_BYTES, MAX_DIFF_EVENTS,
+    MAX_FETCH_BYTES, MAX_FETCH_TXS,
+};
+use super::fork::ForkProof;
 use super::internal_txs::{PeerTx, PeerTxType};
+use super::key::Key;
+use super::mempool::Mempool;
+use super::metrics::{self, Registry};
+use super::peer::{Peer, DEFAULT_STAKE};
 use super::peers::Peers;
+use super::receipt::{tx_id, Receipts, TxId};
 use super::round::{FamousType, Round, RoundEvent};
+use super::snapshot::Snapshot;
 use super::trace_time;
+use super::transaction::ConsensusTransaction;
+use super::tx_cache::{TxCache, DEFAULT_TX_CACHE_BYTES};
+
+pub const DEFAULT_COIN_ROUND_FREQUENCY: u64 = 10;
+
+// events per pull answer, the puller asks for the next page until it has
+// them all
+pub const PULL_PAGE_SIZE: u64 = 256;
+
+const GAUGES_INTERVAL_MS: u64 = 1000;
 
 #[derive(Debug, Clone)]
-pub struct Hashgraph {
+pub struct Hashgraph<S: EventStore = MemEventStore> {
     // pub peers: Arc<RwLock<Peers>>,
-    pub events: Events,
+    key: Key,
+    pub events: Events<S>,
     // todo: remove this unecessary arc mutex
     pub rounds: BTreeMap<u64, Round>, // round_id -> round
-    pub tx_out: Arc<Mutex<Sender<Vec<u8>>>>,
+    pub tx_out: Arc<Mutex<Sender<ConsensusTransaction>>>,
+    pub consensus_index: u64, // index of the next ordered transaction
+    pub consensus_log: ConsensusLog,
+    pub app: Option<AppHandle>,
+    pub last_committed_round: u64,
+    pub state_hash: Vec<u8>, // returned by the last app commit
+    pub event_log: Option<Arc<Mutex<EventLog>>>,
     pub transactions: Vec<Vec<u8>>,
     pub internal_transactions: Vec<PeerTx>,
+    pub forks: HashMap<EventCreator, ForkProof>,
+    // forkers each event sees a proof against, through itself or its
+    // ancestors. Events seeing none are left out
+    pub fork_views: HashMap<EventHash, Vec<EventCreator>>,
+    // included in the next self event
+    pending_peer_txs: Vec<PeerTx>,
+    pending_fork_proofs: Vec<ForkProof>,
+    // first events of each creator in a fast_sync frame, their self-parent
+    // is not part of it
+    frame_roots: HashSet<EventHash>,
+    pub mempool: Mempool,
+    pub receipts: Receipts,
+    // distance between coin rounds in fame elections, 0 disables them
+    pub coin_round_frequency: u64,
+    // microseconds added to the clock for the timestamp of self events, to
+    // test skewed or lying nodes
+    pub clock_skew: i64,
+    // timestamp of the next self events instead of the system clock, for
+    // reproducible simulations
+    pub clock: Option<u64>,
+    // large transaction bodies, to fill compact diffs
+    pub tx_cache: TxCache,
+    // shared with events, see metrics::serve
+    pub metrics: Registry,
+    gauges_updated: Option<Instant>,
+    // counter of the next signed diff, shared by the clones
+    diff_counter: Arc<Mutex<u64>>,
 
     ancestor_cache: HashMap<(EventHash, EventHash), bool>,
     first_decendant_cache: HashMap<(EventHash, EventHash), EventHash>,
@@ -27,19 +87,31 @@ pub struct Hashgraph {
     ss_path_cache: HashMap<(EventHash, EventHash), (bool, Vec<EventCreator>)>,
 }
 
-impl Default for Hashgraph {
-    fn default() -> Hashgraph {
+impl<S: EventStore + Default> Default for Hashgraph<S> {
+    fn default() -> Hashgraph<S> {
         let (tx_out, _) = channel();
 
-        Hashgraph::new(
+        Hashgraph::with_store(
             // Arc::new(RwLock::new(Peers::new())),
+            Key::new_generate().unwrap(),
             Arc::new(Mutex::new(tx_out)),
+            S::default(),
         )
     }
 }
 
-impl Hashgraph {
-    pub fn new(tx_out: Arc<Mutex<Sender<Vec<u8>>>>) -> Hashgraph {
+impl Hashgraph<MemEventStore> {
+    pub fn new(key: Key, tx_out: Arc<Mutex<Sender<ConsensusTransaction>>>) -> Hashgraph {
+        Hashgraph::with_store(key, tx_out, MemEventStore::new())
+    }
+}
+
+impl<S: EventStore> Hashgraph<S> {
+    pub fn with_store(
+        key: Key,
+        tx_out: Arc<Mutex<Sender<ConsensusTransaction>>>,
+        store: S,
+    ) -> Hashgraph<S> {
         // let mut first_round = Round::new(1);
         // let mut rounds = ;
 
@@ -48,13 +120,46 @@ impl Hashgraph {
         // // TODO, dont start at 1
         // rounds.insert(1, Arc::new(RwLock::new(first_round))); // rounds start at 1
 
+        let metrics = Registry::new();
+
+        let mut events = Events::with_store(store);
+
+        events.metrics = metrics.clone();
+
+        // counters from before a restart are not signed again
+        let diff_counter = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() << 32)
+            .unwrap_or(0);
+
         Hashgraph {
             // peers,
-            events: Events::new(),
+            key,
+            events,
             rounds: BTreeMap::new(),
             transactions: vec![],
             internal_transactions: vec![],
+            forks: HashMap::new(),
+            fork_views: HashMap::new(),
+            pending_peer_txs: vec![],
+            pending_fork_proofs: vec![],
+            frame_roots: HashSet::new(),
+            mempool: Mempool::default(),
+            receipts: Receipts::default(),
+            coin_round_frequency: DEFAULT_COIN_ROUND_FREQUENCY,
+            clock_skew: 0,
+            clock: None,
+            tx_cache: TxCache::new(DEFAULT_TX_CACHE_BYTES),
+            metrics,
+            gauges_updated: None,
+            diff_counter: Arc::new(Mutex::new(diff_counter)),
             tx_out,
+            consensus_index: 0,
+            consensus_log: ConsensusLog::default(),
+            app: None,
+            last_committed_round: 0,
+            state_hash: vec![],
+            event_log: None,
             ancestor_cache: HashMap::new(),
             first_decendant_cache: HashMap::new(),
             self_ancestor_cache: HashMap::new(),
@@ -65,6 +170,8 @@ impl Hashgraph {
 
     // used by first node to setup the first round
     pub fn bootstrap(&mut self, peers: Peers) {
+        self.log(LogEntry::Bootstrap(peers.clone()));
+
         let mut first_round = Round::new(1);
 
         first_round.peers = peers.clone();
@@ -87,29 +194,428 @@ impl Hashgraph {
 
         let last_own_event = last_own_event.unwrap();
 
-        self.insert_event(Event::new(
+        self.insert_self_event(
             last_own_event.id + 1,
-            self_id,
             last_own_event.hash,
-            0,
+            EMPTY_HASH,
             vec![tx],
             peer_txs,
-        ))
+        )
+    }
+
+    // first event of this node's chain, once the rounds are known
+    pub fn add_root_event(&mut self, peer_txs: Vec<PeerTx>) -> bool {
+        self.insert_self_event(0, EMPTY_HASH, EMPTY_HASH, vec![], peer_txs)
+    }
+
+    fn insert_self_event(
+        &mut self,
+        id: u64,
+        self_parent: EventHash,
+        other_parent: EventHash,
+        transactions: Vec<Vec<u8>>,
+        peer_txs: Vec<PeerTx>,
+    ) -> bool {
+        let self_id = self.get_last_decided_peers().self_id;
+
+        let mut peer_txs = peer_txs;
+
+        peer_txs.extend(self.pending_peer_txs.drain(..));
+
+        let batch = self.mempool.drain_batch();
+
+        let mut transactions = transactions;
+
+        transactions.extend(batch.clone());
+
+        let mut event = Event::new(
+            id,
+            self_id,
+            self_parent,
+            other_parent,
+            transactions,
+            peer_txs,
+        );
+
+        event.fork_proofs = self.pending_fork_proofs.clone();
+
+        if let Some(now) = self.clock {
+            event.timestamp = now;
+        }
+
+        if self.clock_skew != 0 {
+            event.timestamp = (event.timestamp as i64 + self.clock_skew) as u64;
+        }
+
+        event.hash = event.calc_hash();
+
+        event.sign(&self.key);
+
+        // an own event must be on disk before any peer can pull it, or a
+        // restart would reuse its id and fork our own chain
+        if let Err(e) = self.persist(&event) {
+            error!("Insert self event: {}", e);
+
+            self.mempool.requeue(batch);
+
+            return false;
+        }
+
+        let event_hash = event.hash;
+
+        if !self.check_and_insert_event(event) {
+            self.mempool.requeue(batch);
+
+            return false;
+        }
+
+        for tx in batch {
+            self.receipts.included(&tx_id(&tx), event_hash);
+        }
+
+        true
+    }
+
+    // queues a transaction for the next self event, None if the mempool is full
+    pub fn submit_tx(&mut self, tx: Vec<u8>) -> Option<TxId> {
+        let id = tx_id(&tx);
+
+        // a peer may include it too if the client sent it there as well
+        if tx.len() >= LAZY_TX_BYTES {
+            self.tx_cache.insert(&tx);
+        }
+
+        if !self.mempool.push(tx) {
+            return None;
+        }
+
+        Some(self.receipts.pending(id))
+    }
+
+    // creates a self event only to carry the pending transactions, for when
+    // there is no peer to sync with
+    pub fn flush_mempool(&mut self) -> bool {
+        if self.mempool.is_empty() {
+            return false;
+        }
+
+        let self_id = self.get_last_decided_peers().self_id;
+
+        match self.events.get_last_event_of(self_id) {
+            Some(last_own_event) => self.insert_self_event(
+                last_own_event.id + 1,
+                last_own_event.hash,
+                EMPTY_HASH,
+                vec![],
+                vec![],
+            ),
+            None => false,
+        }
     }
 
     pub fn insert_event(&mut self, event: Event) -> bool {
+        if !self.check_and_insert_event(event.clone()) {
+            return false;
+        }
+
+        self.log(LogEntry::Event(event));
+
+        true
+    }
+
+    fn check_and_insert_event(&mut self, event: Event) -> bool {
         trace_time!("Insert Event");
 
         let mut event = event.clone();
 
         event.round = 0;
 
-        if !self.events.check_event(&event) {
+        if let Some(known) = self.events.find_fork(&event) {
+            let proof = ForkProof::new(known, event);
+
+            // a forged or corrupted second version proves no fork
+            if !self.check_fork_proof(&proof) {
+                let reason = if proof.second.check_hash() {
+                    "bad_signature"
+                } else {
+                    "bad_hash"
+                };
+
+                metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", reason)]);
+
+                return false;
+            }
+
+            metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "fork")]);
+
+            // carried by no event yet, so the log keeps it until one does
+            if self.add_fork_proof(proof.clone()) {
+                self.log(LogEntry::ForkProof(proof.clone()));
+
+                self.pending_fork_proofs.push(proof);
+            }
+
             return false;
         }
 
+        let peers = self.get_last_populated_round(&event).peers;
+
+        if !self.events.check_event(&event, &peers) {
+            return false;
+        }
+
+        if !event
+            .fork_proofs
+            .iter()
+            .all(|proof| self.check_fork_proof(proof))
+        {
+            debug!("Error: Insert event: Bad fork proof: {:?}", event.hash);
+
+            metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "bad_fork_proof")]);
+
+            return false;
+        }
+
+        for proof in event.fork_proofs.iter() {
+            self.add_fork_proof(proof.clone());
+        }
+
+        self.pending_fork_proofs
+            .retain(|proof| !event.fork_proofs.contains(proof));
+
+        self.insert_checked_event(event)
+    }
+
+    fn log(&self, entry: LogEntry) {
+        if let Some(ref event_log) = self.event_log {
+            if let Err(e) = event_log.lock().unwrap().append(&entry) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    fn persist(&self, event: &Event) -> Result<(), String> {
+        match self.event_log {
+            Some(ref event_log) => event_log
+                .lock()
+                .unwrap()
+                .append(&LogEntry::Event(event.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // replaces the event log with a snapshot and the fork proofs no event
+    // carries yet
+    pub fn compact_log(&self) -> Result<(), String> {
+        let event_log = match self.event_log {
+            Some(ref event_log) => event_log,
+            None => return Ok(()),
+        };
+
+        let mut entries = vec![LogEntry::Snapshot(self.snapshot())];
+
+        entries.extend(
+            self.pending_fork_proofs
+                .iter()
+                .cloned()
+                .map(LogEntry::ForkProof),
+        );
+
+        event_log.lock().unwrap().compact(&entries)
+    }
+
+    // Graphviz view of the retained events, see dot.rs
+    pub fn to_dot(&self) -> String {
+        dot::render(&self.events, &self.rounds)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            events: self.events.all_events(),
+            undecided: self.events.undecided.keys().cloned().collect(),
+            rounds: self.rounds.clone(),
+            forks: self.forks.clone(),
+            fork_views: self.fork_views.clone(),
+            consensus_index: self.consensus_index,
+            last_committed_round: self.last_committed_round,
+            state_hash: self.state_hash.clone(),
+            app_state: self
+                .app
+                .as_ref()
+                .map(|app| app.0.lock().unwrap().snapshot()),
+        }
+    }
+
+    // replaces the whole consensus state, the caches are rebuilt lazily. The
+    // snapshot may come from another node, the rounds are rewritten for ours
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.log(LogEntry::Snapshot(snapshot.clone()));
+
+        if let (Some(ref app), Some(ref app_state)) =
+            (self.app.as_ref(), snapshot.app_state.as_ref())
+        {
+            app.0.lock().unwrap().restore(app_state);
+        }
+
+        self.events.restore(snapshot.events, snapshot.undecided);
+        self.rounds = snapshot.rounds;
+
+        let self_id = Key::pub_to_int(self.key.get_pub());
+
+        for round in self.rounds.values_mut() {
+            round.peers.self_id = self_id;
+        }
+
+        self.forks = snapshot.forks;
+        self.fork_views = snapshot.fork_views;
+        self.consensus_index = snapshot.consensus_index;
+        self.consensus_log.reset(snapshot.consensus_index);
+        self.last_committed_round = snapshot.last_committed_round;
+        self.state_hash = snapshot.state_hash;
+
+        self.ancestor_cache.clear();
+        self.first_decendant_cache.clear();
+        self.self_ancestor_cache.clear();
+        self.ss_cache.clear();
+        self.ss_path_cache.clear();
+    }
+
+    // Rebuilds the state from an event log. Fame and round received are
+    // derived again from the events
+    pub fn replay(&mut self, entries: Vec<LogEntry>) {
+        for entry in entries {
+            match entry {
+                LogEntry::Bootstrap(peers) => self.bootstrap(peers),
+                LogEntry::Snapshot(snapshot) => self.restore(snapshot),
+                LogEntry::Frame(frame, self_id) => {
+                    self.insert_frame(frame, self_id);
+                }
+                LogEntry::Event(event) => {
+                    self.insert_event(event);
+                }
+                LogEntry::ForkProof(proof) => {
+                    if self.add_fork_proof(proof.clone()) {
+                        self.pending_fork_proofs.push(proof);
+                    }
+                }
+            }
+        }
+
+        info!(
+            "Replay: Events {}, Rounds {}",
+            self.events
+                .known_events()
+                .values()
+                .fold(0, |c, id| c + id + 1),
+            self.rounds.len()
+        );
+    }
+
+    // A Leave transaction removes a proven forker from the rounds decided
+    // after this one. It stops counting toward strongly seeing only for the
+    // events that see the proof, see fork_view
+    pub fn add_fork_proof(&mut self, proof: ForkProof) -> bool {
+        let creator = proof.creator();
+
+        if self.forks.contains_key(&creator) {
+            return false;
+        }
+
+        if !self.check_fork_proof(&proof) {
+            debug!("Fork proof: Invalid proof against {}", creator);
+
+            return false;
+        }
+
+        warn!(
+            "Fork proof: {} signed {} and {}",
+            creator, proof.first.id, proof.second.id
+        );
+
+        self.forks.insert(creator, proof);
+
+        if let Some(peer) = self.get_last_decided_peers().get_by_id(creator) {
+            self.pending_peer_txs.push(PeerTx::new_leave(peer));
+        }
+
+        true
+    }
+
+    fn check_fork_proof(&self, proof: &ForkProof) -> bool {
+        proof.verify(&self.get_last_populated_round(&proof.first).peers)
+    }
+
+    // forkers seen by the parents, plus the ones the event brings a proof
+    // against
+    fn fork_view(&self, event: &Event) -> Vec<EventCreator> {
+        let mut view = [event.self_parent, event.other_parent]
+            .iter()
+            .filter_map(|parent| self.fork_views.get(parent))
+            .flat_map(|view| view.iter().cloned())
+            .chain(event.fork_proofs.iter().map(|proof| proof.creator()))
+            .collect::<Vec<EventCreator>>();
+
+        view.sort();
+        view.dedup();
+
+        view
+    }
+
+    // inserts the events of a frame received from fast_sync. The first event
+    // of each creator counts as a root, as its ancestry is not part of the
+    // frame. It keeps its self-parent, as its hash and signature cover it
+    pub fn insert_frame(&mut self, frame: Frame, self_id: u64) -> (usize, usize) {
+        self.log(LogEntry::Frame(frame.clone(), self_id));
+
+        let mut nb_events = 0;
+        let mut nb_peers = 0;
+
+        for (round_id, round_events) in frame.events {
+            let mut peers = round_events.0.clone();
+
+            peers.self_id = self_id;
+
+            nb_peers = peers.len();
+
+            self.rounds.entry(round_id).or_insert_with(|| {
+                let mut round = Round::new(round_id);
+
+                round.peers = peers.clone();
+
+                round
+            });
+
+            for (_, events) in round_events.1 {
+                for (_, event) in events {
+                    let mut event = event.clone();
+
+                    event.round = 0;
+
+                    if !self.events.check_event(&event, &peers) {
+                        continue;
+                    }
+
+                    if self.events.get_last_event_of(event.creator).is_none() {
+                        self.frame_roots.insert(event.hash);
+                    }
+
+                    if self.insert_checked_event(event) {
+                        nb_events += 1;
+                    }
+                }
+            }
+        }
+
+        (nb_events, nb_peers)
+    }
+
+    fn insert_checked_event(&mut self, event: Event) -> bool {
+        let mut event = event.clone();
+
         // let round = self.get_parent_round(event.clone());
 
+        let fork_view = self.fork_view(&event);
+
         event.round = self.get_round_id(event.clone());
 
         if self
@@ -119,18 +625,66 @@ impl Hashgraph {
         {
             debug!("Error: Insert event: Peer not in the round: {:?}", event);
 
+            metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "not_in_round")]);
+
             return false;
         }
 
+        if let Err(e) = self.events.insert_event(event.clone()) {
+            error!("Error: Insert event: {}", e);
+
+            metrics::EVENTS_REJECTED.inc_with(&self.metrics, &[("reason", "store")]);
+
+            return false;
+        }
+
+        if !fork_view.is_empty() {
+            self.fork_views.insert(event.hash, fork_view);
+        }
+
         self.add_to_round(event.clone());
 
-        self.events.insert_event(event.clone());
+        for tx in event.transactions.iter() {
+            if tx.len() >= LAZY_TX_BYTES {
+                self.tx_cache.insert(tx);
+            }
+        }
 
         self.process_fame(event.clone());
 
+        metrics::EVENTS_INSERTED.inc(&self.metrics);
+
+        self.update_gauges();
+
         true
     }
 
+    // at most once per GAUGES_INTERVAL_MS, it runs on every insert
+    fn update_gauges(&mut self) {
+        if let Some(updated) = self.gauges_updated {
+            if updated.elapsed() < Duration::from_millis(GAUGES_INTERVAL_MS) {
+                return;
+            }
+        }
+
+        self.gauges_updated = Some(Instant::now());
+
+        metrics::UNDECIDED_EVENTS.set(&self.metrics, self.events.undecided.len() as f64);
+
+        let caches = [
+            ("ancestor", self.ancestor_cache.len()),
+            ("first_decendant", self.first_decendant_cache.len()),
+            ("self_ancestor", self.self_ancestor_cache.len()),
+            ("strongly_see", self.ss_cache.len()),
+            ("strongly_see_path", self.ss_path_cache.len()),
+            ("tx", self.tx_cache.len()),
+        ];
+
+        for (cache, len) in caches.iter() {
+            metrics::CACHE_ENTRIES.set_with(&self.metrics, &[("cache", *cache)], *len as f64);
+        }
+    }
+
     pub fn get_last_decided_peers(&self) -> Peers {
         // error!("RETURNING LAST PEERS !!!!!!!");
         let round = self.rounds.values().last().unwrap().clone();
@@ -160,6 +714,8 @@ impl Hashgraph {
         }
     }
 
+    // merges the last page of a pull and answers with the first page of the
+    // push, see EventsDiff::known_after for the next ones
     pub fn merge_events(
         &mut self,
         self_id: u64,
@@ -168,18 +724,14 @@ impl Hashgraph {
     ) -> Result<EventsDiff, String> {
         trace_time!("Merge Event");
 
-        let mut merged = 0;
-
-        for (_, events) in other_events.diff {
-            for event in events.values() {
-                self.insert_event(event.clone());
+        let start = Instant::now();
+        let registry = self.metrics.clone();
 
-                merged += 1;
-            }
-        }
+        defer!(metrics::MERGE_EVENTS_SECONDS.observe(&registry, start.elapsed()));
 
-        // todo: post checks to validate other parents
+        let merged = self.merge_page(&other_events);
 
+        // the caller must pull the next pages first
         if other_events.has_more {
             warn!("Has more");
 
@@ -207,24 +759,182 @@ impl Hashgraph {
 
         let last_other_event = last_other_event.unwrap();
 
-        self.insert_event(Event::new(
+        self.insert_self_event(
             last_own_event.id + 1,
-            self_id,
             last_own_event.hash,
             last_other_event.hash,
             vec![],
             vec![],
-        ));
-
-        let mut events_diff = self.events.events_diff(other_events.known, 0);
+        );
 
-        events_diff.sender_id = self_id;
+        let events_diff = self.get_events_diff(peer_id, other_events.known, PULL_PAGE_SIZE);
 
         trace!("Merged Event count: {}", merged);
 
         Ok(events_diff)
     }
 
+    // inserts one page of a pull, parents first, and returns how many events
+    // were new. merge_events calls it on the last page
+    pub fn merge_page(&mut self, other_events: &EventsDiff) -> usize {
+        let mut merged = 0;
+
+        let mut pending = other_events
+            .diff
+            .values()
+            .flat_map(|events| events.values().cloned())
+            .collect::<Vec<Event>>();
+
+        // parents first, whatever the creator. An event whose parents are
+        // still missing is left for a later pull
+        loop {
+            let before = pending.len();
+
+            pending.retain(|event| {
+                let ready = [event.self_parent, event.other_parent]
+                    .iter()
+                    .all(|hash| *hash == EMPTY_HASH || self.events.get_event(hash).is_some());
+
+                if !ready {
+                    return true;
+                }
+
+                if self.insert_event(event.clone()) {
+                    merged += 1;
+                }
+
+                false
+            });
+
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+
+        merged
+    }
+
+    // diff of what the other node misses, signed by this node for
+    // `recipient_id`, 0 when answering a pull
+    pub fn get_events_diff(
+        &self,
+        recipient_id: EventCreator,
+        known: HashMap<EventCreator, u64>,
+        limit: u64,
+    ) -> EventsDiff {
+        let mut events_diff = self.events.events_diff(known, limit);
+
+        events_diff.sender_id = self.get_last_decided_peers().self_id;
+        events_diff.recipient_id = recipient_id;
+
+        {
+            let mut counter = self.diff_counter.lock().unwrap();
+
+            *counter += 1;
+
+            events_diff.counter = *counter;
+        }
+
+        events_diff.sign(&self.key);
+
+        events_diff
+    }
+
+    // same as get_events_diff without the large transaction bodies, see
+    // fill_txs for the receiving side
+    pub fn get_compact_events_diff(
+        &self,
+        recipient_id: EventCreator,
+        known: HashMap<EventCreator, u64>,
+        limit: u64,
+    ) -> EventsDiff {
+        let mut events_diff = self.get_events_diff(recipient_id, known, limit);
+
+        events_diff.strip_txs(LAZY_TX_BYTES);
+
+        events_diff
+    }
+
+    // puts back the left out bodies found here, returns the ones still to
+    // fetch from the sender
+    pub fn fill_txs(&self, events_diff: &mut EventsDiff) -> Vec<TxRef> {
+        let bodies = events_diff
+            .lazy
+            .iter()
+            .filter_map(|tx_ref| {
+                self.tx_cache
+                    .get(&tx_ref.id)
+                    .map(|body| (tx_ref.clone(), body.clone()))
+            })
+            .collect();
+
+        let filled = events_diff.fill_txs(bodies);
+
+        metrics::LAZY_TXS.add_with(&self.metrics, &[("source", "local")], filled as u64);
+
+        events_diff.lazy.clone()
+    }
+
+    // bodies asked by a peer filling a compact diff, each ref answered once.
+    // The events not retained here are skipped, and the answer stops at
+    // MAX_FETCH_TXS refs or MAX_FETCH_BYTES
+    pub fn get_txs(&self, tx_refs: &[TxRef]) -> Vec<(TxRef, Vec<u8>)> {
+        let mut seen = HashSet::new();
+        let mut bytes = 0;
+        let mut res = vec![];
+
+        for tx_ref in tx_refs.iter().take(MAX_FETCH_TXS) {
+            if !seen.insert(tx_ref) {
+                continue;
+            }
+
+            let body = self
+                .events
+                .get_event(&tx_ref.event)
+                .and_then(|event| event.transactions.get(tx_ref.index).cloned());
+
+            if let Some(body) = body {
+                if bytes + body.len() > MAX_FETCH_BYTES {
+                    break;
+                }
+
+                bytes += body.len();
+
+                res.push((tx_ref.clone(), body));
+            }
+        }
+
+        res
+    }
+
+    // returns the sender of a gossiped diff, if it is a known peer that signed it
+    pub fn check_events_diff(&self, events_diff: &EventsDiff) -> Result<Peer, String> {
+        let (count, bytes) = events_diff.size();
+
+        if count > MAX_DIFF_EVENTS || bytes > MAX_DIFF_BYTES {
+            metrics::EVENTS_REJECTED.add_with(&self.metrics, &[("reason", "diff_too_big")], count);
+
+            return Err(format!(
+                "Diff too big from {}: {} events, {} bytes",
+                events_diff.sender_id, count, bytes
+            ));
+        }
+
+        let peer = match self
+            .get_last_decided_peers()
+            .get_by_id(events_diff.sender_id)
+        {
+            Some(peer) => peer,
+            None => return Err(format!("Unknown sender {}", events_diff.sender_id)),
+        };
+
+        if !events_diff.verify(&peer.pub_key) {
+            return Err(format!("Bad signature from {}", peer.id));
+        }
+
+        Ok(peer)
+    }
+
     pub fn is_ancestor(&mut self, possible_ancestor: Event, e: Event) -> bool {
         let hash = (possible_ancestor.hash, e.hash);
 
@@ -329,11 +1039,20 @@ impl Hashgraph {
     }
 
     pub fn _strongly_see(&mut self, e: Event, possible_see: Event) -> bool {
-        let super_majority = self.get_decided_peers(&possible_see).super_majority;
+        let peers = self.get_decided_peers(&possible_see);
+
+        let forkers = self.fork_views.get(&e.hash).cloned().unwrap_or_default();
 
         let res = self.strongly_see_with_path(e, possible_see);
 
-        res.0 == true && res.1.len() >= super_majority as usize
+        let creators = res
+            .1
+            .iter()
+            .filter(|creator| !forkers.contains(creator))
+            .cloned()
+            .collect::<Vec<EventCreator>>();
+
+        res.0 == true && peers.stake_of(&creators) >= peers.super_majority
     }
 
     pub fn strongly_see_with_path(
@@ -398,21 +1117,25 @@ impl Hashgraph {
         (false, vec![])
     }
 
+    fn is_root(&self, e: &Event) -> bool {
+        e.is_root() || self.frame_roots.contains(&e.hash)
+    }
+
     pub fn is_witness(&mut self, e: Event) -> bool {
-        if e.is_root() {
+        if self.is_root(&e) {
             return true;
         }
 
         let last_round = self.get_parent_round(e.clone());
 
-        let mut ss_count = 0;
+        let mut ss_creators = vec![];
 
         for witness_hash in last_round.witnesses.iter() {
             let witness_round_event = last_round.events.get(&witness_hash).unwrap();
             let got_witness = self.events.get_event(&witness_round_event.hash).unwrap();
 
-            if self.strongly_see(e.clone(), got_witness) {
-                ss_count += 1;
+            if self.strongly_see(e.clone(), got_witness.clone()) {
+                ss_creators.push(got_witness.creator);
             }
         }
 
@@ -422,7 +1145,7 @@ impl Hashgraph {
         //     self.get_decided_peers(&e).super_majority
         // );
 
-        ss_count >= last_round.peers.super_majority
+        last_round.peers.stake_of(&ss_creators) >= last_round.peers.super_majority
         // ss_count >= self.get_decided_peers(&e).super_majority
     }
 
@@ -434,7 +1157,7 @@ impl Hashgraph {
         let last_round = self.get_parent_round(e.clone());
         let mut last_round_id = last_round.id;
 
-        if !e.is_root() && is_witness {
+        if !self.is_root(&e) && is_witness {
             last_round_id += 1;
         }
 
@@ -450,6 +1173,10 @@ impl Hashgraph {
         let last_round = self.get_parent_round(e.clone());
         let is_witness = self.is_witness(e.clone());
 
+        // the witnesses of the next rounds already voted without this one,
+        // it would stay undefined and block the round received forever
+        let late = is_witness && self.rounds.get(&e.round).map_or(false, |r| r.decided);
+
         self.rounds
             .entry(e.round)
             .and_modify(|round| {
@@ -465,6 +1192,12 @@ impl Hashgraph {
                 round
             });
 
+        if late {
+            debug!("Late witness {:?} in round {}", e.hash, e.round);
+
+            self.set_famous(e.round, &e.hash, false);
+        }
+
         true
     }
 
@@ -504,6 +1237,12 @@ impl Hashgraph {
         self.rounds.get(&round).unwrap().clone()
     }
 
+    // Virtual voting: every new witness votes on the undecided witnesses of
+    // the previous rounds. One round later it votes whether it sees them, then
+    // it follows the majority of the witnesses it strongly sees, and decides
+    // once that majority is a super majority. Every coin_round_frequency
+    // rounds, a witness without a super majority votes with a bit of its
+    // signature instead, so an adversarial scheduler cannot stall the election.
     pub fn process_fame(&mut self, e: Event) {
         // warn!("PROCESS FAME {} {}", e.round, self.is_witness(e.clone()));
         let first_round_nb = self.rounds.keys().next().unwrap().clone();
@@ -515,7 +1254,6 @@ impl Hashgraph {
         trace_time!("Process Fame");
 
         let prev_round = self.rounds.get(&(e.round - 1)).unwrap().clone();
-        // let mut round = self.rounds.get_mut(&e.round).unwrap();
         let mut round_event = self
             .rounds
             .get(&e.round)
@@ -525,27 +1263,63 @@ impl Hashgraph {
             .unwrap()
             .clone();
 
-        // count votes
-        let mut vote_results = HashMap::new();
+        // witnesses of the previous round whose votes we collect
+        let mut strongly_seen = vec![];
 
         for witness_hash in prev_round.witnesses.iter() {
-            let witness_round_event = prev_round.events.get(witness_hash).unwrap();
-            let wit_hash = witness_round_event.hash;
-            let got_witness = self.events.get_event(&wit_hash).unwrap();
-
-            // vote
-            round_event
-                .votes
-                .insert(wit_hash, self.see(e.clone(), got_witness.clone()));
-
-            // collect votes
-            if self.strongly_see(e.clone(), got_witness) {
-                for (hash, vote) in witness_round_event.clone().votes {
-                    if vote {
-                        *vote_results.entry(hash).or_insert(0) += 1;
-                    }
+            let got_witness = self.events.get_event(witness_hash).unwrap();
+
+            if self.strongly_see(e.clone(), got_witness.clone()) {
+                let stake = prev_round.peers.get_stake(&got_witness.creator);
+
+                strongly_seen.push((stake, prev_round.events.get(witness_hash).unwrap().clone()));
+            }
+        }
+
+        let undecided_rounds = self
+            .rounds
+            .range(first_round_nb..e.round)
+            .filter(|(_, round)| !round.decided && !round.purged)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<u64>>();
+
+        for round_id in undecided_rounds {
+            let round = self.rounds.get(&round_id).unwrap().clone();
+            let distance = e.round - round_id;
+
+            for witness_hash in round.witnesses.iter() {
+                if round.events.get(witness_hash).unwrap().famous != FamousType::Undefined {
+                    continue;
                 }
+
+                let vote = if distance == 1 {
+                    let got_witness = self.events.get_event(witness_hash).unwrap();
+
+                    self.see(e.clone(), got_witness)
+                } else {
+                    let (yes, no) = Self::tally_votes(&strongly_seen, witness_hash);
+
+                    let majority = yes >= no;
+                    let tally = if majority { yes } else { no };
+                    let super_majority = tally >= prev_round.peers.super_majority;
+
+                    if !self.is_coin_round(distance) {
+                        if super_majority {
+                            self.set_famous(round_id, witness_hash, majority);
+                        }
+
+                        majority
+                    } else if super_majority {
+                        majority
+                    } else {
+                        Self::coin_flip(&e)
+                    }
+                };
+
+                round_event.votes.insert(witness_hash.clone(), vote);
             }
+
+            self.update_round_decided(round_id);
         }
 
         self.rounds
@@ -554,22 +1328,62 @@ impl Hashgraph {
             .events
             .insert(e.hash, round_event.clone());
 
-        for (hash, votes) in vote_results.iter() {
-            // let super_majority = self.get_decided_peers(&e).super_majority;
+        self.decide_round_received();
+    }
 
-            // let mut rounds = self.rounds.write().unwrap();
-            let mut prev_prev_round = self.rounds.get_mut(&(e.round - 2)).unwrap();
-            let super_majority = prev_prev_round.peers.super_majority;
+    // stake voting yes and no about `hash`, among (stake, voter) witnesses
+    pub fn tally_votes(voters: &[(u64, RoundEvent)], hash: &EventHash) -> (u64, u64) {
+        voters
+            .iter()
+            .fold((0, 0), |(yes, no), &(stake, ref voter)| {
+                match voter.votes.get(hash) {
+                    Some(true) => (yes.saturating_add(stake), no),
+                    Some(false) => (yes, no.saturating_add(stake)),
+                    None => (yes, no),
+                }
+            })
+    }
 
-            let is_famous = if votes.clone() >= super_majority {
-                FamousType::True
-            } else {
-                FamousType::False
-            };
-            prev_prev_round.events.get_mut(hash).unwrap().famous = is_famous.clone();
-        }
+    pub fn is_coin_round(&self, distance: u64) -> bool {
+        self.coin_round_frequency > 0 && distance % self.coin_round_frequency == 0
+    }
 
-        self.decide_round_received();
+    // middle bit of the signature, unpredictable before the event is signed
+    pub fn coin_flip(e: &Event) -> bool {
+        e.signature
+            .get(e.signature.len() / 2)
+            .map_or(false, |byte| byte & 1 == 1)
+    }
+
+    fn set_famous(&mut self, round_id: u64, hash: &EventHash, famous: bool) {
+        let round = self.rounds.get_mut(&round_id).unwrap();
+
+        round.events.get_mut(hash).unwrap().famous = if famous {
+            FamousType::True
+        } else {
+            FamousType::False
+        };
+    }
+
+    fn update_round_decided(&mut self, round_id: u64) {
+        let round = self.rounds.get_mut(&round_id).unwrap();
+
+        let decided = round
+            .witnesses
+            .iter()
+            .all(|hash| round.events.get(hash).unwrap().famous != FamousType::Undefined);
+
+        round.decided = decided;
+
+        metrics::ROUND_WITNESSES.set_with(
+            &self.metrics,
+            &[("round", &round_id.to_string())],
+            round.witnesses.len() as f64,
+        );
+
+        if decided {
+            metrics::LAST_DECIDED_ROUND.set(&self.metrics, round_id as f64);
+        }
     }
 
     pub fn decide_round_received(&mut self) {
@@ -599,17 +1413,20 @@ impl Hashgraph {
                     break;
                 }
 
-                let famous = witness_iter.filter(|(_, e)| e.famous == FamousType::True);
+                // famous witnesses without stake have no say
+                let famous = witness_iter
+                    .filter(|(_, e)| e.famous == FamousType::True)
+                    .map(|(hash, _)| self.events.get_event(&hash).unwrap())
+                    .filter(|witness| round.peers.get_stake(&witness.creator) > 0)
+                    .collect::<Vec<Event>>();
 
-                if famous.clone().count() == 0 {
+                if famous.len() == 0 {
                     break;
                 }
 
                 let mut decided = true;
 
-                for (hash, _) in famous {
-                    let got_witness = self.events.get_event(&hash).unwrap();
-
+                for got_witness in famous {
                     if !self.see(got_witness, undecided.clone()) {
                         decided = false;
 
@@ -656,7 +1473,7 @@ impl Hashgraph {
     pub fn consensus_order(&mut self, decided_events: Vec<EventHash>) {
         trace_time!("Consensus Order");
 
-        let mut received = decided_events
+        let received = decided_events
             .iter()
             .map(|hash| {
                 let event = self.events.get_event(&hash).unwrap();
@@ -669,8 +1486,6 @@ impl Hashgraph {
             })
             .collect::<Vec<(Event, Round, RoundEvent)>>();
 
-        received.sort_by(|(_, _, re1), (_, _, re2)| re1.received.cmp(&re2.received));
-
         let mut timestamped = received
             .iter()
             .map(|(e, r, re)| {
@@ -687,7 +1502,26 @@ impl Hashgraph {
             })
             .collect::<Vec<(Event, Round, u64)>>();
 
-        timestamped.sort_by(|(_, _, t1), (_, _, t2)| t1.cmp(t2));
+        let mut whitenings = HashMap::new();
+
+        for (_, r, _) in timestamped.iter() {
+            if !whitenings.contains_key(&r.id) {
+                whitenings.insert(r.id, self.get_whitening(r));
+            }
+        }
+
+        // round received, then consensus timestamp, then whitened signature.
+        // The hash only breaks ties between identical signatures
+        timestamped.sort_by(|(e1, r1, t1), (e2, r2, t2)| {
+            r1.id
+                .cmp(&r2.id)
+                .then(t1.cmp(t2))
+                .then_with(|| {
+                    Self::whiten(&e1.signature, &whitenings[&r1.id])
+                        .cmp(&Self::whiten(&e2.signature, &whitenings[&r2.id]))
+                })
+                .then(e1.hash.cmp(&e2.hash))
+        });
 
         // cleanup old events
         let max_round = timestamped
@@ -710,9 +1544,11 @@ impl Hashgraph {
                     tuple.0.transactions.clone(),
                     tuple.0.internal_transactions.clone(),
                     tuple.1.clone(),
+                    tuple.0.clone(),
+                    tuple.2,
                 )
             })
-            .collect::<Vec<(Vec<Vec<u8>>, Vec<PeerTx>, Round)>>();
+            .collect::<Vec<(Vec<Vec<u8>>, Vec<PeerTx>, Round, Event, u64)>>();
 
         if txs.len() > 0 {
             for tx in txs.clone() {
@@ -722,7 +1558,36 @@ impl Hashgraph {
 
                     for item in tx.0.clone() {
                         if item.len() > 0 {
-                            out.send(item).unwrap();
+                            let consensus_tx = ConsensusTransaction {
+                                index: self.consensus_index,
+                                data: item,
+                                event_hash: tx.3.hash,
+                                creator: tx.3.creator,
+                                round_received: tx.2.id,
+                                timestamp: tx.4,
+                            };
+
+                            if let Some(ref app) = self.app {
+                                app.0.lock().unwrap().deliver_tx(&consensus_tx);
+                            }
+
+                            let latency = self.receipts.ordered(
+                                &tx_id(&consensus_tx.data),
+                                &consensus_tx.event_hash,
+                                consensus_tx.index,
+                                consensus_tx.timestamp,
+                                consensus_tx.round_received,
+                            );
+
+                            if let Some(latency) = latency {
+                                metrics::CONSENSUS_LATENCY_SECONDS.observe(&self.metrics, latency);
+                            }
+
+                            self.consensus_log.push(consensus_tx.clone());
+
+                            out.send(consensus_tx).unwrap();
+
+                            self.consensus_index += 1;
                         }
                     }
                 }
@@ -756,7 +1621,7 @@ impl Hashgraph {
                                         .get_mut(&round)
                                         .unwrap()
                                         .peers
-                                        .add(item.peer.clone());
+                                        .add(item.peer.clone().with_stake(DEFAULT_STAKE));
                                 }
                             }
                             if item.tx_type == PeerTxType::Leave {
@@ -768,6 +1633,16 @@ impl Hashgraph {
                                         .remove(item.peer.clone());
                                 }
                             }
+                            // a vote of the creator, see Peers::endorse_stake
+                            if item.tx_type == PeerTxType::Stake {
+                                for round in rounds_to_modify.clone() {
+                                    self.rounds.get_mut(&round).unwrap().peers.endorse_stake(
+                                        tx.3.creator,
+                                        item.peer.id,
+                                        item.peer.stake,
+                                    );
+                                }
+                            }
                         }
                     }
                 }
@@ -776,6 +1651,53 @@ impl Hashgraph {
                 self.internal_transactions.extend(tx.1);
             }
         }
+
+        let last_received = timestamped.last().unwrap().1.id;
+
+        self.commit_rounds(last_received);
+
+        // the whole batch is delivered, a snapshot now replays it all
+        let compact = self.event_log.as_ref().map_or(false, |event_log| {
+            event_log.lock().unwrap().needs_compaction()
+        });
+
+        if compact {
+            if let Err(e) = self.compact_log() {
+                error!("{}", e);
+            }
+        }
+    }
+
+    // every round up to the last received one is final
+    fn commit_rounds(&mut self, last_received: u64) {
+        let app = match self.app {
+            Some(ref app) => app.clone(),
+            None => return,
+        };
+
+        for round in self.last_committed_round + 1..=last_received {
+            self.state_hash = app.0.lock().unwrap().commit(round);
+            self.last_committed_round = round;
+        }
+    }
+
+    // XOR of the signatures of the famous witnesses of a round. No creator
+    // can predict it when signing, so it cannot game the tie-break
+    pub fn get_whitening(&self, round: &Round) -> Vec<u8> {
+        round
+            .witnesses
+            .iter()
+            .filter(|hash| round.events.get(hash).unwrap().famous == FamousType::True)
+            .filter_map(|hash| self.events.get_event(hash))
+            .fold(vec![], |acc, witness| {
+                Self::whiten(&acc, &witness.signature)
+            })
+    }
+
+    pub fn whiten(a: &[u8], b: &[u8]) -> Vec<u8> {
+        (0..max(a.len(), b.len()))
+            .map(|i| a.get(i).unwrap_or(&0) ^ b.get(i).unwrap_or(&0))
+            .collect()
     }
 
     pub fn get_consensus_timestamp(&mut self, event: Event, round: &Round) -> u64 {
@@ -912,9 +1834,8 @@ impl Hashgraph {
                 .insert(i, (round.peers.clone(), creator_events));
         }
 
-        for peer_events in frame.events.values_mut().next().unwrap().1.values_mut() {
-            peer_events.iter_mut().next().unwrap().1.self_parent = 0;
-        }
+        frame.sender_id = self.get_last_decided_peers().self_id;
+        frame.sign(&self.key);
 
         frame
     }
@@ -926,27 +1847,43 @@ impl Hashgraph {
 
         trace_time!("Purge");
 
+        let mut purged_rounds = vec![];
+
         let events_to_remove = self
             .rounds
             .iter_mut()
             .rev()
             .skip_while(|(id, _)| id > &&(max_round - 5))
-            .map(|(_, round)| {
+            .map(|(id, round)| {
                 if round.purged {
                     return vec![];
                 }
 
-                let hashes = round.events.keys().cloned().collect::<Vec<u64>>();
+                let hashes = round.events.keys().cloned().collect::<Vec<EventHash>>();
 
                 round.purge();
 
+                purged_rounds.push(*id);
+
                 hashes
             })
             .flatten()
-            .collect::<Vec<u64>>();
+            .collect::<Vec<EventHash>>();
+
+        for id in purged_rounds.iter() {
+            metrics::ROUND_WITNESSES.remove_with(&self.metrics, &[("round", &id.to_string())]);
+        }
+
+        metrics::PURGED_ROUNDS.add(&self.metrics, purged_rounds.len() as u64);
+        metrics::PURGED_EVENTS.add(&self.metrics, events_to_remove.len() as u64);
 
         self.events.purge(events_to_remove.clone());
 
+        for hash in events_to_remove.iter() {
+            self.fork_views.remove(hash);
+            self.frame_roots.remove(hash);
+        }
+
         self.ancestor_cache = Self::purge_cache(&events_to_remove, &self.ancestor_cache);
         self.first_decendant_cache =
             Self::purge_cache(&events_to_remove, &self.first_decendant_cache);
@@ -956,7 +1893,7 @@ impl Hashgraph {
     }
 
     fn purge_cache<T: Clone>(
-        hash: &Vec<u64>,
+        hash: &Vec<EventHash>,
         cache: &HashMap<(EventHash, EventHash), T>,
     ) -> HashMap<(EventHash, EventHash), T> {
         cache
@@ -1,20 +1,54 @@
 mod hashgraph_tests {
     use std::collections::HashMap;
+    use std::net::SocketAddr;
     use std::sync::mpsc::{channel, Receiver};
-    use std::sync::{Arc, Mutex};
-
-    use event::Event;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use event::{Event, EventHash, EMPTY_HASH};
+    use event_log::EventLog;
+    use event_store::{EventStore, FileEventStore, MemEventStore};
+    use events::{EventsDiff, LAZY_TX_BYTES};
+    use app::{AppHandle, Application};
+    use consensus_log::ConsensusLog;
     use hashgraph::Hashgraph;
     use internal_txs::PeerTx;
+    use key::Key;
+    use mempool::{Mempool, MempoolConfig};
+    use node::{Node, NodeConfig};
+    use metrics::{self, Kind, Metric, Registry};
     #[allow(unused_imports)]
     use peer::Peer;
     use peers::Peers;
+    use receipt::{tx_id, Receipt, Receipts};
     #[allow(unused_imports)]
-    use round::FamousType;
+    use round::{FamousType, RoundEvent};
+    use snapshot::Snapshot;
+    use transaction::ConsensusTransaction;
+    use transport::{Handler, MemTransport, Transport};
 
     // new_hash, other_parent
     type EventInsert = (String, String, String, Vec<PeerTx>);
 
+    lazy_static! {
+        static ref KEYS: Mutex<HashMap<u64, Key>> = Mutex::new(HashMap::new());
+    }
+
+    // creates a peer and keeps its key around to sign its events
+    fn new_peer(addr: &str) -> Peer {
+        let key = Key::new_generate().unwrap();
+        let peer = Peer::new(addr.parse().unwrap(), key.get_pub());
+
+        KEYS.lock().unwrap().insert(peer.id, key);
+
+        peer
+    }
+
+    fn get_key(peer_id: u64) -> Key {
+        KEYS.lock().unwrap().get(&peer_id).unwrap().clone()
+    }
+
     #[allow(dead_code)]
     fn insert_events(
         hg: &mut Hashgraph,
@@ -29,7 +63,7 @@ mod hashgraph_tests {
                 .map(|p| p.clone())
                 .collect::<Vec<Peer>>();
 
-            peers.sort_by(|p1, p2| p1.pub_key.cmp(&p2.pub_key));
+            peers.sort_by(|p1, p2| p1.address.port().cmp(&p2.address.port()));
 
             let event_hash_bytes = event.0.as_bytes();
             let peers_txs = event.3.clone();
@@ -41,9 +75,9 @@ mod hashgraph_tests {
 
             let self_p = indexes
                 .get(&String::from_utf8(self_hash.to_vec()).unwrap())
-                .map_or(0, |ev: &Event| ev.hash);
+                .map_or(EMPTY_HASH, |ev: &Event| ev.hash);
 
-            let other_p = indexes.get(&event.1).map_or(0, |ev: &Event| ev.hash);
+            let other_p = indexes.get(&event.1).map_or(EMPTY_HASH, |ev: &Event| ev.hash);
 
             let tx = event.2.clone().into_bytes();
             let mut txs = vec![];
@@ -52,7 +86,7 @@ mod hashgraph_tests {
                 txs = vec![tx];
             }
 
-            let e = Event::new(
+            let mut e = Event::new(
                 (idx - 48) as u64,
                 peers[(peer - 97) as usize].id,
                 self_p,
@@ -61,6 +95,8 @@ mod hashgraph_tests {
                 peers_txs,
             );
 
+            e.sign(&get_key(e.creator));
+
             // println!("INSERT {}", event.0);
 
             if !hg.insert_event(e.clone()) {
@@ -77,16 +113,11 @@ mod hashgraph_tests {
     fn insert_events_create(
         to_insert: Vec<EventInsert>,
         peers: Peers,
-    ) -> (Hashgraph, HashMap<String, Event>, Receiver<Vec<u8>>) {
+    ) -> (Hashgraph, HashMap<String, Event>, Receiver<ConsensusTransaction>) {
         let mut peers = peers.clone();
         let mut indexes = HashMap::new();
         let (tx_out, tx_out_recv) = channel();
 
-        let mut hg = Hashgraph::new(
-            // Arc::new(RwLock::new(peers.clone())),
-            Arc::new(Mutex::new(tx_out)),
-        );
-
         let mut vpeers = peers
             .clone()
             .get_peers()
@@ -94,10 +125,16 @@ mod hashgraph_tests {
             .map(|p| p.clone())
             .collect::<Vec<Peer>>();
 
-        vpeers.sort_by(|p1, p2| p1.pub_key.cmp(&p2.pub_key));
+        vpeers.sort_by(|p1, p2| p1.address.port().cmp(&p2.address.port()));
 
         peers.self_id = vpeers[0].id;
 
+        let mut hg = Hashgraph::new(
+            // Arc::new(RwLock::new(peers.clone())),
+            get_key(vpeers[0].id),
+            Arc::new(Mutex::new(tx_out)),
+        );
+
         hg.bootstrap(peers.clone());
 
         for event in to_insert.iter() {
@@ -110,9 +147,9 @@ mod hashgraph_tests {
 
             let self_p = indexes
                 .get(&String::from_utf8(self_hash.to_vec()).unwrap())
-                .map_or(0, |ev: &Event| ev.hash);
+                .map_or(EMPTY_HASH, |ev: &Event| ev.hash);
 
-            let other_p = indexes.get(&event.1).map_or(0, |ev: &Event| ev.hash);
+            let other_p = indexes.get(&event.1).map_or(EMPTY_HASH, |ev: &Event| ev.hash);
 
             let tx = event.2.clone().into_bytes();
             let mut txs = vec![];
@@ -121,7 +158,7 @@ mod hashgraph_tests {
                 txs = vec![tx];
             }
 
-            let e = Event::new(
+            let mut e = Event::new(
                 (idx - 48) as u64,
                 vpeers[(peer - 97) as usize].id,
                 self_p,
@@ -130,6 +167,8 @@ mod hashgraph_tests {
                 peers_txs,
             );
 
+            e.sign(&get_key(e.creator));
+
             if !hg.insert_event(e.clone()) {
                 panic!("Cannot insert event");
             }
@@ -154,8 +193,8 @@ mod hashgraph_tests {
     fn simple_test() {
         let mut peers = Peers::new();
 
-        let peer1 = Peer::new("127.0.0.1:1".parse().unwrap(), vec![0]);
-        let peer2 = Peer::new("127.0.0.1:2".parse().unwrap(), vec![1]);
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
 
         peers.add(peer1.clone());
         peers.add(peer2.clone());
@@ -318,6 +357,416 @@ mod hashgraph_tests {
         assert_first_decendant("b0", "b1", "b1");
     }
 
+    #[test]
+    fn hash_test() {
+        let e1 = Event::new(1, 42, [1; 32], [2; 32], vec![vec![3]], vec![]);
+
+        let mut e2 = e1.clone();
+
+        e2.signature = vec![4];
+        e2.round = 5;
+
+        // neither the signature nor the local round are part of the hash
+        assert_eq!(e1.calc_hash(), e2.calc_hash());
+        assert!(e1.check_hash());
+
+        let mut e3 = e1.clone();
+
+        e3.other_parent = [3; 32];
+
+        assert!(e1.calc_hash() != e3.calc_hash());
+        assert!(!e3.check_hash());
+
+        // peer ids do not depend on the platform or the Rust version
+        assert_eq!(Key::pub_to_int(vec![0; 32]), 0x66687aadf862bd77);
+    }
+
+    #[test]
+    fn signature_test() {
+        let mut peers = Peers::new();
+
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+
+        peers.add(peer1.clone());
+        peers.add(peer2.clone());
+
+        let to_insert = vec![("a0".to_string(), "".to_string(), "".to_string(), vec![])];
+
+        let (mut hg, _, _) = insert_events_create(to_insert, peers);
+
+        // unsigned
+        let unsigned = Event::new(0, peer2.id, EMPTY_HASH, EMPTY_HASH, vec![], vec![]);
+
+        assert_eq!(hg.insert_event(unsigned.clone()), false);
+
+        // signed by someone else
+        let mut forged = unsigned.clone();
+
+        forged.sign(&get_key(peer1.id));
+
+        assert_eq!(hg.insert_event(forged), false);
+
+        // tampered after signature
+        let mut tampered = unsigned.clone();
+
+        tampered.sign(&get_key(peer2.id));
+        tampered.transactions = vec![vec![42]];
+
+        assert_eq!(hg.insert_event(tampered), false);
+
+        let mut signed = unsigned.clone();
+
+        signed.sign(&get_key(peer2.id));
+
+        assert_eq!(hg.insert_event(signed), true);
+    }
+
+    #[test]
+    fn events_diff_signature_test() {
+        let mut peers = Peers::new();
+
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+        let peer3 = new_peer("127.0.0.1:3");
+
+        peers.add(peer1.clone());
+        peers.add(peer2.clone());
+
+        let to_insert = vec![
+            ("a0".to_string(), "".to_string(), "".to_string(), vec![]),
+            ("b0".to_string(), "".to_string(), "".to_string(), vec![]),
+        ];
+
+        let (hg, _, _) = insert_events_create(to_insert, peers);
+
+        let diff = hg.get_events_diff(0, HashMap::new(), 0);
+
+        assert_eq!(hg.check_events_diff(&diff).unwrap().id, peer1.id);
+
+        // impersonation of another known peer
+        let mut impersonated = diff.clone();
+
+        impersonated.sender_id = peer2.id;
+
+        assert!(hg.check_events_diff(&impersonated).is_err());
+
+        // unknown sender
+        let mut unknown = diff.clone();
+
+        unknown.sender_id = peer3.id;
+        unknown.sign(&get_key(peer3.id));
+
+        assert!(hg.check_events_diff(&unknown).is_err());
+
+        // tampered content
+        let mut tampered = diff.clone();
+
+        tampered.diff.clear();
+
+        assert!(hg.check_events_diff(&tampered).is_err());
+
+        // readdressed or replayed
+        let mut readdressed = diff.clone();
+
+        readdressed.recipient_id = peer2.id;
+
+        assert!(hg.check_events_diff(&readdressed).is_err());
+
+        let mut replayed = diff.clone();
+
+        replayed.counter += 1;
+
+        assert!(hg.check_events_diff(&replayed).is_err());
+
+        // each diff gets its own counter
+        assert!(hg.get_events_diff(0, HashMap::new(), 0).counter > diff.counter);
+    }
+
+    #[test]
+    fn push_replay_test() {
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+
+        let mut peers = Peers::new();
+
+        peers.add_self(peer1.clone());
+        peers.add(peer2.clone());
+
+        let (tx_out, _) = channel();
+
+        let store: Box<EventStore> = Box::new(MemEventStore::new());
+
+        let mut hg = Hashgraph::with_store(get_key(peer1.id), Arc::new(Mutex::new(tx_out)), store);
+
+        hg.bootstrap(peers.clone());
+
+        assert!(hg.add_root_event(vec![]));
+
+        let mut b0 = Event::new(0, peer2.id, EMPTY_HASH, EMPTY_HASH, vec![], vec![]);
+
+        b0.sign(&get_key(peer2.id));
+
+        assert!(hg.insert_event(b0));
+
+        let handler = Handler {
+            node: Arc::new(RwLock::new(Node::default())),
+            hg: Arc::new(RwLock::new(hg)),
+            peers: Arc::new(RwLock::new(peers)),
+            served: Default::default(),
+            pushed: Default::default(),
+        };
+
+        let push = |recipient_id: u64, counter: u64| {
+            let mut diff = EventsDiff::default();
+
+            diff.sender_id = peer2.id;
+            diff.recipient_id = recipient_id;
+            diff.counter = counter;
+            diff.sign(&get_key(peer2.id));
+
+            diff
+        };
+
+        let first = push(peer1.id, 10);
+
+        assert!(handler.push(first.clone()));
+
+        // the same push again, or an older one
+        assert!(!handler.push(first));
+        assert!(!handler.push(push(peer1.id, 9)));
+
+        // a push meant for another node
+        assert!(!handler.push(push(peer2.id, 11)));
+
+        assert!(handler.push(push(peer1.id, 11)));
+    }
+
+    #[test]
+    fn fork_test() {
+        let mut peers = Peers::new();
+
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+
+        peers.add(peer1.clone());
+        peers.add(peer2.clone());
+
+        let to_insert = vec![
+            ("a0".to_string(), "".to_string(), "".to_string(), vec![]),
+            ("b0".to_string(), "".to_string(), "".to_string(), vec![]),
+            ("b1".to_string(), "a0".to_string(), "".to_string(), vec![]),
+        ];
+
+        let (mut hg, indexes, _) = insert_events_create(to_insert, peers);
+
+        let b0 = indexes.get("b0").unwrap().clone();
+
+        // signed by someone else, it proves no fork of b
+        let mut forged = Event::new(1, peer2.id, b0.hash, EMPTY_HASH, vec![vec![2]], vec![]);
+
+        forged.sign(&get_key(peer1.id));
+
+        assert_eq!(hg.insert_event(forged), false);
+        assert!(hg.forks.get(&peer2.id).is_none());
+
+        let rejected = metrics::render(&hg.metrics);
+
+        assert!(rejected.contains("reason=\"bad_signature\""));
+        assert!(!rejected.contains("reason=\"fork\""));
+
+        // same id and self-parent as b1, different content
+        let mut fork = Event::new(1, peer2.id, b0.hash, EMPTY_HASH, vec![vec![1]], vec![]);
+
+        fork.sign(&get_key(peer2.id));
+
+        assert_eq!(hg.insert_event(fork.clone()), false);
+        assert!(metrics::render(&hg.metrics).contains("reason=\"fork\""));
+
+        let proof = hg.forks.get(&peer2.id).unwrap().clone();
+
+        assert!(proof.verify(&hg.get_last_decided_peers()));
+
+        // portable: a proof made of events from two creators is rejected
+        let mut bad_proof = proof.clone();
+
+        bad_proof.second = indexes.get("a0").unwrap().clone();
+
+        assert!(!bad_proof.verify(&hg.get_last_decided_peers()));
+
+        // the forker leaves through the next self event
+        assert!(hg.add_self_event(vec![], vec![]));
+
+        let last = hg.events.get_last_event_of(peer1.id).unwrap();
+
+        assert_eq!(last.internal_transactions.len(), 1);
+        assert_eq!(last.internal_transactions[0].peer.id, peer2.id);
+        assert_eq!(last.fork_proofs, vec![proof.clone()]);
+
+        // and is ignored when counting strongly seeing paths, but only by
+        // the events that see the proof
+        let a0 = indexes.get("a0").unwrap().clone();
+        let b1 = indexes.get("b1").unwrap().clone();
+
+        assert_eq!(hg.strongly_see(b1.clone(), a0.clone()), true);
+
+        let mut b2 = Event::new(2, peer2.id, b1.hash, last.hash, vec![], vec![]);
+
+        b2.sign(&get_key(peer2.id));
+
+        assert!(hg.insert_event(b2.clone()));
+        assert_eq!(hg.fork_views.get(&b2.hash), Some(&vec![peer2.id]));
+        assert_eq!(hg.strongly_see(b2, a0), false);
+
+        // a carried proof must hold
+        let mut bad = Event::new(2, peer1.id, last.hash, EMPTY_HASH, vec![], vec![]);
+
+        bad.fork_proofs = vec![bad_proof];
+        bad.hash = bad.calc_hash();
+        bad.sign(&get_key(peer1.id));
+
+        assert_eq!(hg.insert_event(bad), false);
+    }
+
+    #[test]
+    fn coin_round_test() {
+        let mut hg: Hashgraph = Hashgraph::default();
+
+        hg.coin_round_frequency = 3;
+
+        assert!(!hg.is_coin_round(2));
+        assert!(hg.is_coin_round(3));
+        assert!(hg.is_coin_round(6));
+
+        hg.coin_round_frequency = 0;
+
+        assert!(!hg.is_coin_round(3));
+
+        let mut e = Event::new(0, 0, EMPTY_HASH, EMPTY_HASH, vec![], vec![]);
+
+        e.signature = vec![0, 0, 1, 0];
+
+        assert!(Hashgraph::<MemEventStore>::coin_flip(&e));
+
+        e.signature = vec![0, 0, 2, 0];
+
+        assert!(!Hashgraph::<MemEventStore>::coin_flip(&e));
+    }
+
+    #[test]
+    fn stake_test() {
+        let mut peers = Peers::new();
+
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2").with_stake(10);
+        let peer3 = new_peer("127.0.0.1:3");
+
+        peers.add(peer1.clone());
+        peers.add(peer2.clone());
+        peers.add(peer3.clone());
+
+        assert_eq!(peers.total_stake, 12);
+        assert_eq!(peers.super_majority, 9);
+
+        // each creator counts once
+        assert_eq!(peers.stake_of(&vec![peer2.id, peer2.id]), 10);
+        assert_eq!(peers.stake_of(&vec![peer1.id, peer3.id]), 2);
+
+        peers.set_stake(peer2.id, 1);
+
+        assert_eq!(peers.total_stake, 3);
+        assert_eq!(peers.super_majority, 3);
+
+        // a stake change needs a super majority of the stake behind it
+        assert!(!peers.endorse_stake(peer1.id, peer1.id, 100));
+        assert!(!peers.endorse_stake(peer3.id, peer1.id, 100));
+        assert_eq!(peers.get_stake(&peer1.id), 1);
+
+        assert!(peers.endorse_stake(peer2.id, peer1.id, 100));
+        assert_eq!(peers.get_stake(&peer1.id), 100);
+
+        // huge stakes saturate instead of overflowing
+        peers.set_stake(peer2.id, u64::max_value());
+
+        assert_eq!(peers.total_stake, u64::max_value());
+        assert!(peers.super_majority < u64::max_value());
+
+        /*
+            b1
+           /|
+          / |
+        a1  |
+        | \ |
+        |  \|
+        a0  b0
+        */
+
+        let mut peers = Peers::new();
+
+        peers.add(peer1.clone().with_stake(1));
+        peers.add(peer2.clone().with_stake(10));
+
+        let to_insert = vec![
+            ("a0".to_string(), "".to_string(), "".to_string(), vec![]),
+            ("b0".to_string(), "".to_string(), "".to_string(), vec![]),
+            ("a1".to_string(), "b0".to_string(), "".to_string(), vec![]),
+            ("b1".to_string(), "a1".to_string(), "".to_string(), vec![]),
+        ];
+
+        let (mut hg, indexes, _) = insert_events_create(to_insert, peers);
+
+        let get = |name: &str| indexes.get(name).unwrap().clone();
+
+        // a alone does not hold a super majority of the stake
+        assert_eq!(hg.strongly_see(get("a1"), get("a0")), false);
+        assert_eq!(hg.strongly_see(get("a1"), get("b0")), true);
+
+        // but strongly seeing b0 is enough to make a1 a witness,
+        // unlike with equal stakes in simple_test
+        assert_eq!(hg.is_witness(get("a1")), true);
+        assert_eq!(hg.events.get_event(&get("a1").hash).unwrap().round, 2);
+    }
+
+    #[test]
+    fn stake_vote_test() {
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+        let peer3 = new_peer("127.0.0.1:3");
+
+        let candidate = Event::new(0, peer1.id, EMPTY_HASH, EMPTY_HASH, vec![], vec![]);
+
+        let voter = |vote: bool| {
+            let mut voter = RoundEvent::from_event(candidate.clone());
+
+            voter.votes.insert(candidate.hash, vote);
+
+            voter
+        };
+
+        let tally = |voters: &[(u64, RoundEvent)]| {
+            Hashgraph::<MemEventStore>::tally_votes(voters, &candidate.hash)
+        };
+
+        // equal stakes, the two no win
+        let voters = vec![(1, voter(true)), (1, voter(false)), (1, voter(false))];
+
+        assert_eq!(tally(&voters), (1, 2));
+
+        // the same votes, but the yes holds a super majority of the stake
+        let mut peers = Peers::new();
+
+        peers.add(peer1.clone().with_stake(10));
+        peers.add(peer2.clone());
+        peers.add(peer3.clone());
+
+        let voters = vec![(10, voter(true)), (1, voter(false)), (1, voter(false))];
+        let (yes, no) = tally(&voters);
+
+        assert_eq!((yes, no), (10, 2));
+        assert!(yes >= peers.super_majority);
+        assert!(no < peers.super_majority);
+    }
+
     /*
     |   b4  |
     |   |   |
@@ -341,9 +790,9 @@ mod hashgraph_tests {
     fn complex_test() {
         let mut peers = Peers::new();
 
-        let peer1 = Peer::new("127.0.0.1:1".parse().unwrap(), vec![0]);
-        let peer2 = Peer::new("127.0.0.1:2".parse().unwrap(), vec![1]);
-        let peer3 = Peer::new("127.0.0.1:3".parse().unwrap(), vec![2]);
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+        let peer3 = new_peer("127.0.0.1:3");
 
         peers.add(peer1.clone());
         peers.add(peer2.clone());
@@ -512,16 +961,22 @@ mod hashgraph_tests {
     fn create_consensus_hashgraph() -> (Hashgraph, HashMap<String, Event>, Peers) {
         let mut peers = Peers::new();
 
-        let peer1 = Peer::new("127.0.0.1:1".parse().unwrap(), vec![0]);
-        let peer2 = Peer::new("127.0.0.1:2".parse().unwrap(), vec![1]);
-        let peer3 = Peer::new("127.0.0.1:3".parse().unwrap(), vec![2]);
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+        let peer3 = new_peer("127.0.0.1:3");
 
         peers.add(peer1.clone());
         peers.add(peer2.clone());
         peers.add(peer3.clone());
 
-        // (name, other_parent)
-        let to_insert = vec![
+        let (hg, indexes, _) = insert_events_create(consensus_events(), peers.clone());
+
+        (hg, indexes, peers)
+    }
+
+    // (name, other_parent, tx, peer_txs) of the graph above
+    fn consensus_events() -> Vec<EventInsert> {
+        vec![
             ("a0".to_string(), "".to_string(), "".to_string(), vec![]),
             ("b0".to_string(), "".to_string(), "".to_string(), vec![]),
             ("c0".to_string(), "".to_string(), "".to_string(), vec![]),
@@ -552,34 +1007,1486 @@ mod hashgraph_tests {
             ("a9".to_string(), "c8".to_string(), "".to_string(), vec![]),
             ("b9".to_string(), "a9".to_string(), "".to_string(), vec![]),
             ("c9".to_string(), "b9".to_string(), "".to_string(), vec![]),
+        ]
+    }
+
+    #[test]
+    fn late_witness_test() {
+        let mut peers = Peers::new();
+
+        for port in 1..=4 {
+            peers.add(new_peer(&format!("127.0.0.1:{}", port)));
+        }
+
+        // d only shows up once a, b and c have decided rounds 1 to 3 alone
+        let mut to_insert = vec![("d0".to_string(), "".to_string(), "".to_string(), vec![])];
+
+        to_insert.extend(consensus_events());
+
+        let (mut hg, mut indexes, _) = insert_events_create(to_insert, peers);
+
+        for r in 1..=3 {
+            assert!(hg.rounds.get(&r).unwrap().decided);
+        }
+
+        let late = vec![
+            ("d1".to_string(), "c9".to_string(), "".to_string(), vec![]),
+            ("d2".to_string(), "c9".to_string(), "".to_string(), vec![]),
         ];
 
-        let (hg, indexes, _) = insert_events_create(to_insert, peers.clone());
+        assert!(insert_events(&mut hg, &mut indexes, late));
 
-        (hg, indexes, peers)
+        // too late to be famous, the rounds stay decided for round received
+        for (r, name) in vec![(2, "d1"), (3, "d2")] {
+            let round = hg.rounds.get(&r).unwrap();
+            let hash = indexes.get(name).unwrap().hash;
+
+            assert!(round.witnesses.contains(&hash));
+            assert_eq!(round.events.get(&hash).unwrap().famous, FamousType::False);
+            assert!(round.decided);
+        }
     }
 
-    #[test]
-    fn consensus_test() {
-        let (hg, indexes, _) = create_consensus_hashgraph();
+    // the graph above with d0 added, seen by c2 only. a1 takes c1 instead of
+    // c2 so that d0 reaches the round 2 witness of c and none other
+    fn election_events() -> Vec<EventInsert> {
+        let mut events = vec![("d0".to_string(), "".to_string(), "".to_string(), vec![])];
 
-        let assert_round = |hash: &str, res: u64| {
-            assert_eq!(
-                hg.events
-                    .get_event(&indexes.get(hash).unwrap().clone().hash)
-                    .unwrap()
-                    .round,
-                res
-            );
-        };
+        for mut event in consensus_events() {
+            match event.0.as_str() {
+                "a1" => event.1 = "c1".to_string(),
+                "c2" => event.1 = "d0".to_string(),
+                _ => (),
+            }
 
-        let assert_famous = |r: u64, hash: &str, res: FamousType| {
-            let round = hg.rounds.get(&r).unwrap().clone();
+            events.push(event);
+        }
 
-            assert_eq!(
-                round
-                    .events
-                    .get(&indexes.get(hash).unwrap().clone().hash)
+        events
+    }
+
+    // inserts the election events up to `until` excluded, then the rest once
+    // `check` ran
+    fn election<F>(
+        coin_round_frequency: u64,
+        until: &str,
+        check: F,
+    ) -> (Hashgraph, HashMap<String, Event>)
+    where
+        F: Fn(&Hashgraph, &HashMap<String, Event>),
+    {
+        let mut peers = Peers::new();
+
+        for port in 1..=4 {
+            peers.add(new_peer(&format!("127.0.0.1:{}", port)));
+        }
+
+        let (mut hg, mut indexes, _) = insert_events_create(vec![], peers);
+
+        hg.coin_round_frequency = coin_round_frequency;
+
+        let mut events = election_events();
+        let idx = events.iter().position(|event| event.0 == until).unwrap();
+        let rest = events.split_off(idx);
+
+        assert!(insert_events(&mut hg, &mut indexes, events));
+
+        check(&hg, &indexes);
+
+        assert!(insert_events(&mut hg, &mut indexes, rest));
+
+        (hg, indexes)
+    }
+
+    fn famous(hg: &Hashgraph, indexes: &HashMap<String, Event>, name: &str) -> FamousType {
+        let event = indexes.get(name).unwrap();
+
+        let round = hg.rounds.get(&event.round).unwrap();
+
+        round.events.get(&event.hash).unwrap().famous.clone()
+    }
+
+    fn vote(
+        hg: &Hashgraph,
+        indexes: &HashMap<String, Event>,
+        voter: &str,
+        name: &str,
+    ) -> Option<bool> {
+        let voter = indexes.get(voter).unwrap();
+        let hash = indexes.get(name).unwrap().hash;
+        let round = hg.rounds.get(&voter.round).unwrap();
+
+        round.events.get(&voter.hash).unwrap().votes.get(&hash).cloned()
+    }
+
+    #[test]
+    fn fame_election_test() {
+        // round 2 witnesses: b2, c3 and a2, only c3 sees d0. Round 3: b5, a6
+        // and c5, round 4: b7, c7 and a8, round 5: b9 and c9
+        let (hg, indexes) = election(10, "b7", |hg, indexes| {
+            // one yes against two no at distance 2, not a super majority
+            assert_eq!(vote(hg, indexes, "c3", "d0"), Some(true));
+            assert_eq!(vote(hg, indexes, "b2", "d0"), Some(false));
+            assert_eq!(famous(hg, indexes, "d0"), FamousType::Undefined);
+            assert!(!hg.rounds.get(&1).unwrap().decided);
+
+            // the round 3 witnesses carry the majority over
+            for voter in vec!["b5", "a6", "c5"] {
+                assert_eq!(vote(hg, indexes, voter, "d0"), Some(false));
+            }
+
+            // the others were decided at distance 2
+            for name in vec!["a0", "b0", "c0"] {
+                assert_eq!(famous(hg, indexes, name), FamousType::True);
+            }
+        });
+
+        // b7 strongly sees the three no at distance 3
+        assert_eq!(famous(&hg, &indexes, "d0"), FamousType::False);
+        assert!(hg.rounds.get(&1).unwrap().decided);
+
+        // distance 3 is a coin round: the super majority is voted, not decided
+        let (hg, indexes) = election(3, "b9", |hg, indexes| {
+            for voter in vec!["b7", "c7", "a8"] {
+                assert_eq!(vote(hg, indexes, voter, "d0"), Some(false));
+            }
+
+            assert_eq!(famous(hg, indexes, "d0"), FamousType::Undefined);
+        });
+
+        assert_eq!(famous(&hg, &indexes, "d0"), FamousType::False);
+        assert!(hg.rounds.get(&1).unwrap().decided);
+
+        // distance 2 is a coin round: without a super majority each round 3
+        // witness votes with its coin
+        let coin = |indexes: &HashMap<String, Event>, voter: &str| {
+            Hashgraph::<MemEventStore>::coin_flip(indexes.get(voter).unwrap())
+        };
+
+        let (hg, indexes) = election(2, "b7", |hg, indexes| {
+            for voter in vec!["b5", "a6", "c5"] {
+                assert_eq!(vote(hg, indexes, voter, "d0"), Some(coin(indexes, voter)));
+            }
+        });
+
+        // and distance 3 decides only if the three coins agree
+        let coins = vec!["b5", "a6", "c5"]
+            .into_iter()
+            .map(|voter| coin(&indexes, voter))
+            .collect::<Vec<bool>>();
+
+        let expected = if coins.iter().all(|c| *c) {
+            FamousType::True
+        } else if coins.iter().all(|c| !*c) {
+            FamousType::False
+        } else {
+            FamousType::Undefined
+        };
+
+        assert_eq!(famous(&hg, &indexes, "d0"), expected);
+    }
+
+    // Kahn's algorithm, picking the ready events by ascending or descending hash
+    fn topological_order(events: &Vec<Event>, reverse: bool) -> Vec<Event> {
+        let mut remaining = events.clone();
+        let mut inserted: Vec<EventHash> = vec![EMPTY_HASH];
+        let mut res = vec![];
+
+        remaining.sort_by(|e1, e2| e1.hash.cmp(&e2.hash));
+
+        if reverse {
+            remaining.reverse();
+        }
+
+        while remaining.len() > 0 {
+            let idx = remaining
+                .iter()
+                .position(|e| {
+                    inserted.contains(&e.self_parent) && inserted.contains(&e.other_parent)
+                })
+                .unwrap();
+
+            let event = remaining.remove(idx);
+
+            inserted.push(event.hash);
+            res.push(event);
+        }
+
+        res
+    }
+
+    fn replay(peers: Peers, events: Vec<Event>) -> Vec<ConsensusTransaction> {
+        let (tx_out, tx_out_recv) = channel();
+
+        let mut hg = Hashgraph::new(
+            Key::new_generate().unwrap(),
+            Arc::new(Mutex::new(tx_out)),
+        );
+
+        hg.bootstrap(peers);
+
+        for event in events {
+            assert!(hg.insert_event(event));
+        }
+
+        tx_out_recv.try_iter().collect()
+    }
+
+    #[test]
+    fn consensus_order_test() {
+        let (_, indexes, peers) = create_consensus_hashgraph();
+
+        let events = indexes.values().cloned().collect::<Vec<Event>>();
+
+        let order1 = topological_order(&events, false);
+        let order2 = topological_order(&events, true);
+
+        assert!(order1 != order2);
+
+        let txs1 = replay(peers.clone(), order1);
+        let txs2 = replay(peers.clone(), order2);
+
+        assert!(txs1.len() > 0);
+        assert_eq!(txs1, txs2);
+
+        assert_eq!(txs1[0].data, "c1".to_string().into_bytes());
+        assert_eq!(txs1[0].event_hash, indexes.get("c1").unwrap().hash);
+        assert_eq!(txs1[0].creator, indexes.get("c1").unwrap().creator);
+
+        for (i, tx) in txs1.iter().enumerate() {
+            let event = indexes.values().find(|e| e.hash == tx.event_hash).unwrap();
+
+            assert_eq!(tx.index, i as u64);
+            assert!(tx.round_received > event.round);
+        }
+    }
+
+    struct TestApp {
+        delivered: Arc<Mutex<Vec<ConsensusTransaction>>>,
+        commits: Arc<Mutex<Vec<u64>>>,
+    }
+
+    impl Application for TestApp {
+        fn deliver_tx(&mut self, tx: &ConsensusTransaction) {
+            self.delivered.lock().unwrap().push(tx.clone());
+        }
+
+        fn commit(&mut self, round: u64) -> Vec<u8> {
+            self.commits.lock().unwrap().push(round);
+
+            vec![self.delivered.lock().unwrap().len() as u8]
+        }
+
+        fn snapshot(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn restore(&mut self, _snapshot: &[u8]) {}
+    }
+
+    #[test]
+    fn app_test() {
+        let (_, indexes, peers) = create_consensus_hashgraph();
+
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let commits = Arc::new(Mutex::new(vec![]));
+
+        let (tx_out, tx_out_recv) = channel();
+
+        let mut hg = Hashgraph::new(
+            Key::new_generate().unwrap(),
+            Arc::new(Mutex::new(tx_out)),
+        );
+
+        hg.app = Some(AppHandle::new(TestApp {
+            delivered: delivered.clone(),
+            commits: commits.clone(),
+        }));
+
+        hg.bootstrap(peers);
+
+        let events = indexes.values().cloned().collect::<Vec<Event>>();
+
+        for event in topological_order(&events, false) {
+            assert!(hg.insert_event(event));
+        }
+
+        let out = tx_out_recv.try_iter().collect::<Vec<ConsensusTransaction>>();
+
+        // same stream as the channel, each round committed once and in order
+        assert_eq!(*delivered.lock().unwrap(), out);
+
+        let commits = commits.lock().unwrap().clone();
+
+        assert!(commits.len() > 0);
+        assert_eq!(commits, (1..=hg.last_committed_round).collect::<Vec<u64>>());
+        assert_eq!(hg.state_hash, vec![out.len() as u8]);
+    }
+
+    #[test]
+    fn event_log_test() {
+        use std::fs::{self, OpenOptions};
+        use std::io::Write;
+
+        let (_, indexes, peers) = create_consensus_hashgraph();
+
+        let dir = ::std::env::temp_dir()
+            .join(format!("hg_event_log_test_{}", ::std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        let (tx_out, tx_out_recv) = channel();
+
+        let mut hg = Hashgraph::new(
+            Key::new_generate().unwrap(),
+            Arc::new(Mutex::new(tx_out)),
+        );
+
+        hg.event_log = Some(Arc::new(Mutex::new(EventLog::open(&dir).unwrap())));
+
+        hg.bootstrap(peers);
+
+        let events = indexes.values().cloned().collect::<Vec<Event>>();
+
+        for event in topological_order(&events, false) {
+            assert!(hg.insert_event(event));
+        }
+
+        let out = tx_out_recv.try_iter().collect::<Vec<ConsensusTransaction>>();
+
+        // simulates a crash in the middle of an append
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join("events.log"))
+            .unwrap()
+            .write_all(&[42, 0, 0, 0, 1, 2, 3])
+            .unwrap();
+
+        let len = fs::metadata(dir.join("events.log")).unwrap().len();
+
+        let entries = EventLog::open(&dir).unwrap().read_all().unwrap();
+
+        assert_eq!(fs::metadata(dir.join("events.log")).unwrap().len(), len - 7);
+
+        let (tx_out, tx_out_recv) = channel();
+
+        let mut recovered = Hashgraph::new(
+            Key::new_generate().unwrap(),
+            Arc::new(Mutex::new(tx_out)),
+        );
+
+        recovered.replay(entries);
+
+        let replayed = tx_out_recv.try_iter().collect::<Vec<ConsensusTransaction>>();
+
+        assert_eq!(replayed, out);
+        assert_eq!(recovered.rounds.len(), hg.rounds.len());
+
+        // compacted to a snapshot, the log rebuilds the same state
+        hg.compact_log().unwrap();
+
+        let (tx_out, tx_out_recv) = channel();
+
+        let mut compacted = Hashgraph::new(
+            Key::new_generate().unwrap(),
+            Arc::new(Mutex::new(tx_out)),
+        );
+
+        compacted.replay(EventLog::open(&dir).unwrap().read_all().unwrap());
+
+        assert_eq!(tx_out_recv.try_iter().count(), 0);
+        assert_eq!(compacted.consensus_index, hg.consensus_index);
+        assert_eq!(compacted.rounds.len(), hg.rounds.len());
+        assert_eq!(compacted.events.known_events(), hg.events.known_events());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restart_test() {
+        use std::fs;
+
+        let peer = new_peer("127.0.0.1:1");
+
+        let mut peers = Peers::new();
+
+        peers.add_self(peer.clone());
+
+        let dir = ::std::env::temp_dir()
+            .join(format!("hg_restart_test_{}", ::std::process::id()));
+
+        let _ = fs::remove_dir_all(&dir);
+
+        let (tx_out, _) = channel();
+
+        let mut hg = Hashgraph::new(get_key(peer.id), Arc::new(Mutex::new(tx_out)));
+
+        hg.event_log = Some(Arc::new(Mutex::new(EventLog::open(&dir).unwrap())));
+
+        hg.bootstrap(peers);
+
+        assert!(hg.add_root_event(vec![]));
+        assert!(hg.add_self_event(vec![1], vec![]));
+
+        let last = hg.events.get_last_event_of(peer.id).unwrap();
+
+        // crash, then restart from the log
+        let (tx_out, _) = channel();
+
+        let mut restarted = Hashgraph::new(get_key(peer.id), Arc::new(Mutex::new(tx_out)));
+
+        restarted.replay(EventLog::open(&dir).unwrap().read_all().unwrap());
+
+        assert_eq!(restarted.events.get_last_event_of(peer.id), Some(last.clone()));
+
+        assert!(restarted.add_self_event(vec![2], vec![]));
+
+        let next = restarted.events.get_last_event_of(peer.id).unwrap();
+
+        assert_eq!(next.id, last.id + 1);
+        assert_eq!(next.self_parent, last.hash);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_test() {
+        let (hg, _, peers) = create_consensus_hashgraph();
+
+        let bytes = hg.snapshot().to_bytes();
+
+        let mut restored: Hashgraph = Hashgraph::default();
+
+        restored.restore(Snapshot::from_bytes(&bytes).unwrap());
+
+        assert_eq!(restored.events.known_events(), hg.events.known_events());
+        assert_eq!(restored.consensus_index, hg.consensus_index);
+        assert_eq!(restored.rounds.len(), hg.rounds.len());
+
+        for (id, round) in &hg.rounds {
+            let other = restored.rounds.get(id).unwrap();
+
+            assert_eq!(other.events, round.events);
+            assert_eq!(other.witnesses, round.witnesses);
+            assert_eq!(other.decided, round.decided);
+            assert_eq!(other.peers.len(), round.peers.len());
+        }
+
+        // into a node with another key, which keeps its own chain going
+        let self_id = hg.get_last_decided_peers().self_id;
+
+        let other_id = *peers
+            .clone()
+            .get_peers()
+            .keys()
+            .find(|id| **id != self_id)
+            .unwrap();
+
+        let (tx_out, _tx_out_recv) = channel();
+
+        let mut other = Hashgraph::new(get_key(other_id), Arc::new(Mutex::new(tx_out)));
+
+        other.restore(Snapshot::from_bytes(&bytes).unwrap());
+
+        assert!(other.rounds.values().all(|round| round.peers.self_id == other_id));
+
+        let last_id = other.events.get_last_event_of(other_id).unwrap().id;
+
+        assert!(other.add_self_event(vec![1], vec![]));
+        assert_eq!(
+            other.events.get_last_event_of(other_id).unwrap().id,
+            last_id + 1
+        );
+
+        // unknown versions are refused instead of misread
+        let mut bytes = bytes;
+
+        bytes[6] += 1;
+
+        assert!(Snapshot::from_bytes(&bytes).is_err());
+        assert!(Snapshot::from_bytes(b"garbage").is_err());
+    }
+
+    #[test]
+    fn event_store_test() {
+        use std::fs;
+
+        let (hg, _, _) = create_consensus_hashgraph();
+
+        let dir = ::std::env::temp_dir()
+            .join(format!("hg_event_store_test_{}", ::std::process::id()));
+
+        let mut mem = MemEventStore::new();
+        let mut file = FileEventStore::open(&dir).unwrap();
+
+        let events = hg.events.all_events();
+
+        for event in events.clone() {
+            mem.insert(event.clone()).unwrap();
+            file.insert(event).unwrap();
+        }
+
+        let stores: Vec<&mut EventStore> = vec![&mut mem, &mut file];
+
+        for store in stores {
+            let mut creators = store.creators();
+
+            creators.sort();
+
+            assert_eq!(creators.len(), 3);
+
+            for event in &events {
+                assert_eq!(store.get(&event.hash).as_ref(), Some(event));
+                assert_eq!(store.get_by_id(event.creator, event.id).as_ref(), Some(event));
+            }
+
+            let creator = creators[0];
+            let last = store.last_of(creator).unwrap();
+
+            let chain = store.range(creator, 0, last.id);
+
+            assert_eq!(chain.last(), Some(&last));
+            assert!(chain.windows(2).all(|w| w[0].id + 1 == w[1].id));
+
+            store.purge(&[last.hash]);
+
+            assert_eq!(store.get(&last.hash), None);
+            assert_eq!(store.last_of(creator).unwrap().id, last.id - 1);
+            assert_eq!(store.range(creator, last.id, last.id), vec![]);
+        }
+
+        // purging most of the events shrinks the file
+        let mut file = FileEventStore::open(&dir.join("compact")).unwrap();
+
+        for event in events.clone() {
+            file.insert(event).unwrap();
+        }
+
+        let path = dir.join("compact").join("events.db");
+        let full = fs::metadata(&path).unwrap().len();
+
+        let (purged, kept) = events.split_at(events.len() - 2);
+
+        file.purge(&purged.iter().map(|e| e.hash).collect::<Vec<EventHash>>());
+
+        assert!(fs::metadata(&path).unwrap().len() < full);
+
+        for event in purged {
+            assert_eq!(file.get(&event.hash), None);
+        }
+
+        for event in kept {
+            assert_eq!(file.get(&event.hash).as_ref(), Some(event));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mempool_test() {
+        let peer = new_peer("127.0.0.1:1");
+
+        let mut peers = Peers::new();
+
+        peers.add_self(peer.clone());
+
+        let (tx_out, _) = channel();
+
+        let mut hg = Hashgraph::new(get_key(peer.id), Arc::new(Mutex::new(tx_out)));
+
+        hg.mempool = Mempool::new(MempoolConfig {
+            max_event_txs: 2,
+            max_event_bytes: 3,
+            max_pool_txs: 4,
+            max_pool_bytes: 8,
+        });
+
+        hg.bootstrap(peers);
+
+        assert!(hg.add_root_event(vec![]));
+
+        // nothing to flush
+        assert!(!hg.flush_mempool());
+
+        assert!(hg.mempool.push(vec![1]));
+        assert!(hg.mempool.push(vec![2, 2]));
+        assert!(hg.mempool.push(vec![3, 3]));
+        assert!(hg.mempool.push(vec![4, 4, 4]));
+
+        // backpressure once full, and a tx bigger than an event never fits
+        assert!(!hg.mempool.push(vec![5]));
+        assert!(!Mempool::default().push(vec![0; 64 * 1024 + 1]));
+
+        assert!(hg.flush_mempool());
+
+        let event = hg.events.get_last_event_of(peer.id).unwrap();
+
+        assert_eq!(event.transactions, vec![vec![1], vec![2, 2]]);
+
+        // the byte limit cuts the next batch
+        assert!(hg.flush_mempool());
+
+        let event = hg.events.get_last_event_of(peer.id).unwrap();
+
+        assert_eq!(event.transactions, vec![vec![3, 3]]);
+
+        assert!(hg.flush_mempool());
+        assert!(hg.mempool.is_empty());
+    }
+
+    #[test]
+    fn receipt_test() {
+        let peer = new_peer("127.0.0.1:1");
+
+        let mut peers = Peers::new();
+
+        peers.add_self(peer.clone());
+
+        let (tx_out, _) = channel();
+
+        let mut hg = Hashgraph::new(get_key(peer.id), Arc::new(Mutex::new(tx_out)));
+
+        hg.bootstrap(peers);
+
+        assert!(hg.add_root_event(vec![]));
+
+        let id = hg.submit_tx(vec![1, 2, 3]).unwrap();
+
+        assert_eq!(hg.receipts.get(&id), Receipt::Pending);
+        assert_eq!(hg.receipts.get(&tx_id(&[1, 2, 3])), Receipt::Unknown);
+
+        // the same transaction again is another submission
+        let again = hg.submit_tx(vec![1, 2, 3]).unwrap();
+
+        assert!(again != id);
+        assert_eq!(hg.receipts.get(&again), Receipt::Pending);
+
+        assert!(hg.flush_mempool());
+
+        let event = hg.events.get_last_event_of(peer.id).unwrap();
+
+        for id in [id, again].iter() {
+            match hg.receipts.get(id) {
+                Receipt::Included(hash) => assert_eq!(hash, event.hash),
+                Receipt::Ordered { .. } => (),
+                other => panic!("Unexpected receipt {:?}", other),
+            }
+        }
+
+        let mut receipts = Receipts::new(2);
+
+        let one = receipts.pending(tx_id(&[1]));
+        let two = receipts.pending(tx_id(&[2]));
+
+        // the same transaction in another event is not ours
+        assert!(receipts.ordered(&tx_id(&[2]), &EMPTY_HASH, 7, 42, 3).is_none());
+        assert_eq!(receipts.get(&two), Receipt::Pending);
+
+        receipts.included(&tx_id(&[2]), [1; 32]);
+        assert!(receipts.ordered(&tx_id(&[2]), &[1; 32], 7, 42, 3).is_some());
+
+        // bounded, the oldest submissions are forgotten
+        let three = receipts.pending(tx_id(&[3]));
+
+        // the latency is only reported once
+        assert!(receipts.ordered(&tx_id(&[2]), &[1; 32], 7, 42, 3).is_none());
+
+        assert_eq!(receipts.get(&one), Receipt::Unknown);
+        assert_eq!(
+            receipts.get(&two),
+            Receipt::Ordered {
+                index: 7,
+                timestamp: 42,
+                round_received: 3,
+            }
+        );
+        assert_eq!(receipts.get(&three), Receipt::Pending);
+
+        // a resubmission never downgrades the first one
+        let mut receipts = Receipts::new(2);
+
+        let first = receipts.pending(tx_id(&[1]));
+
+        receipts.included(&tx_id(&[1]), [1; 32]);
+
+        let second = receipts.pending(tx_id(&[1]));
+
+        assert!(second != first);
+        assert_eq!(receipts.get(&first), Receipt::Included([1; 32]));
+        assert_eq!(receipts.get(&second), Receipt::Pending);
+    }
+
+    #[test]
+    fn consensus_log_test() {
+        use std::time::Duration;
+
+        let log = ConsensusLog::new(2);
+
+        let tx = |index| ConsensusTransaction {
+            index,
+            data: vec![index as u8],
+            event_hash: EMPTY_HASH,
+            creator: 0,
+            round_received: 1,
+            timestamp: 0,
+        };
+
+        assert_eq!(log.read_from(0, 10, Duration::from_millis(1)), Ok(vec![]));
+
+        log.push(tx(0));
+        log.push(tx(1));
+        log.push(tx(2));
+
+        // index 0 was dropped to stay within capacity
+        assert_eq!(log.first_index(), Some(1));
+        assert_eq!(log.read_from(0, 10, Duration::from_millis(1)), Err(1));
+        assert_eq!(log.read_from(1, 10, Duration::from_millis(1)), Ok(vec![tx(1), tx(2)]));
+        assert_eq!(log.read_from(2, 1, Duration::from_millis(1)), Ok(vec![tx(2)]));
+
+        // nothing new before the timeout
+        assert_eq!(log.read_from(3, 10, Duration::from_millis(1)), Ok(vec![]));
+
+        // independent subscribers, each with its own cursor
+        let mut sub1 = log.subscribe(1);
+        let mut sub2 = log.subscribe(2);
+
+        assert_eq!(sub1.recv(), Ok(tx(1)));
+        assert_eq!(sub1.recv(), Ok(tx(2)));
+        assert_eq!(sub2.recv(), Ok(tx(2)));
+        assert_eq!(sub1.recv_timeout(Duration::from_millis(1)), Ok(None));
+
+        log.push(tx(3));
+
+        assert_eq!(sub1.recv(), Ok(tx(3)));
+        assert_eq!(sub2.recv(), Ok(tx(3)));
+        assert_eq!(sub1.cursor(), 4);
+
+        // a consumer resuming from an index no longer retained is told so
+        assert_eq!(log.subscribe(0).recv(), Err(2));
+
+        // after a restore, nothing before the snapshot cursor can be served
+        log.reset(10);
+
+        assert_eq!(log.read_from(4, 10, Duration::from_millis(1)), Err(10));
+
+        log.push(tx(10));
+
+        assert_eq!(log.subscribe(10).next(), Some(tx(10)));
+    }
+
+    #[test]
+    fn metrics_test() {
+        use std::time::Duration;
+
+        const COUNTER: Metric = Metric {
+            name: "test_counter_total",
+            help: "Test counter",
+            kind: Kind::Counter,
+        };
+
+        const SUMMARY: Metric = Metric {
+            name: "test_seconds",
+            help: "Test summary",
+            kind: Kind::Summary,
+        };
+
+        let registry = Registry::new();
+
+        COUNTER.inc_with(&registry, &[("reason", "a")]);
+        COUNTER.inc_with(&registry, &[("reason", "a")]);
+        COUNTER.inc_with(&registry, &[("reason", "b\"c")]);
+        SUMMARY.observe(&registry, Duration::from_millis(500));
+        SUMMARY.observe(&registry, Duration::from_millis(1500));
+
+        let out = metrics::render(&registry);
+
+        assert!(out.contains("# HELP test_counter_total Test counter\n"));
+        assert!(out.contains("# TYPE test_counter_total counter\n"));
+        assert!(out.contains("test_counter_total{reason=\"a\"} 2\n"));
+        assert!(out.contains("test_counter_total{reason=\"b\\\"c\"} 1\n"));
+        assert!(out.contains("# TYPE test_seconds summary\n"));
+        assert!(out.contains("test_seconds_sum 2\n"));
+        assert!(out.contains("test_seconds_count 2\n"));
+
+        COUNTER.remove_with(&registry, &[("reason", "a")]);
+
+        assert!(!metrics::render(&registry).contains("test_counter_total{reason=\"a\"}"));
+
+        // each hashgraph counts its own events
+        let (hg, _, _) = create_consensus_hashgraph();
+        let other = Hashgraph::<MemEventStore>::default();
+
+        assert!(metrics::render(&hg.metrics).contains("hashgraph_events_inserted_total"));
+        assert!(!metrics::render(&other.metrics).contains("hashgraph_events_inserted_total"));
+    }
+
+    #[test]
+    fn dot_test() {
+        let (hg, indexes, _) = create_consensus_hashgraph();
+
+        let dot = hg.to_dot();
+
+        let id = |name: &str| {
+            indexes[name]
+                .hash
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+
+        let a0 = &indexes["a0"];
+
+        assert!(dot.starts_with("digraph hashgraph {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains(&format!("subgraph cluster_{} {{", a0.creator)));
+        assert!(dot.contains(&format!("subgraph cluster_{}_1 {{", a0.creator)));
+
+        // famous witness, decided
+        let a0_line = dot
+            .lines()
+            .find(|line| line.trim_left().starts_with(&format!("\"{}\" [", id("a0"))))
+            .unwrap();
+
+        assert!(a0_line.contains("peripheries=2"));
+        assert!(a0_line.contains("fillcolor=palegreen"));
+        assert!(a0_line.contains("\\nreceived "));
+
+        // self-parent and other-parent edges
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\";", id("a1"), id("a0"))));
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}\" [style=dashed, color=blue];",
+            id("b1"),
+            id("a0")
+        )));
+    }
+
+    // a store whose writes fail once full
+    #[derive(Debug, Default)]
+    struct FullStore {
+        store: MemEventStore,
+        full: Arc<Mutex<bool>>,
+    }
+
+    impl EventStore for FullStore {
+        fn insert(&mut self, event: Event) -> Result<(), String> {
+            if *self.full.lock().unwrap() {
+                return Err("Store full".to_string());
+            }
+
+            self.store.insert(event)
+        }
+
+        fn get(&self, hash: &EventHash) -> Option<Event> {
+            self.store.get(hash)
+        }
+
+        fn get_by_id(&self, creator: u64, id: u64) -> Option<Event> {
+            self.store.get_by_id(creator, id)
+        }
+
+        fn last_of(&self, creator: u64) -> Option<Event> {
+            self.store.last_of(creator)
+        }
+
+        fn creators(&self) -> Vec<u64> {
+            self.store.creators()
+        }
+
+        fn range(&self, creator: u64, from: u64, to: u64) -> Vec<Event> {
+            self.store.range(creator, from, to)
+        }
+
+        fn purge(&mut self, hashes: &[EventHash]) {
+            self.store.purge(hashes)
+        }
+    }
+
+    #[test]
+    fn store_error_test() {
+        let peer = new_peer("127.0.0.1:1");
+
+        let mut peers = Peers::new();
+
+        peers.add_self(peer.clone());
+
+        let (tx_out, _) = channel();
+
+        let store = FullStore::default();
+        let full = store.full.clone();
+
+        let mut hg = Hashgraph::with_store(get_key(peer.id), Arc::new(Mutex::new(tx_out)), store);
+
+        hg.bootstrap(peers);
+
+        assert!(hg.add_root_event(vec![]));
+
+        *full.lock().unwrap() = true;
+
+        hg.submit_tx(b"tx".to_vec()).unwrap();
+
+        // refused whole, the transaction waits for the next event
+        assert!(!hg.add_self_event(vec![], vec![]));
+        assert_eq!(hg.events.undecided.len(), 1);
+        assert_eq!(hg.events.get_last_event_of(peer.id).unwrap().id, 0);
+        assert_eq!(hg.mempool.len(), 1);
+        assert!(metrics::render(&hg.metrics).contains("reason=\"store\""));
+
+        *full.lock().unwrap() = false;
+
+        assert!(hg.add_self_event(vec![], vec![]));
+        assert_eq!(hg.events.get_last_event_of(peer.id).unwrap().id, 1);
+    }
+
+    #[test]
+    fn transport_test() {
+        let peer = new_peer("127.0.0.1:1");
+
+        let mut peers = Peers::new();
+
+        peers.add_self(peer.clone());
+
+        let (tx_out, _) = channel();
+
+        let store: Box<EventStore> = Box::new(MemEventStore::new());
+
+        let mut hg = Hashgraph::with_store(get_key(peer.id), Arc::new(Mutex::new(tx_out)), store);
+
+        hg.bootstrap(peers.clone());
+
+        assert!(hg.add_root_event(vec![]));
+        assert!(hg.add_self_event(vec![7; LAZY_TX_BYTES], vec![]));
+
+        let tx_ref = hg.get_compact_events_diff(0, HashMap::new(), 0).lazy[0].clone();
+
+        let handler = Handler {
+            node: Arc::new(RwLock::new(Node::default())),
+            hg: Arc::new(RwLock::new(hg)),
+            peers: Arc::new(RwLock::new(peers)),
+            served: Default::default(),
+            pushed: Default::default(),
+        };
+
+        let network = MemTransport::new();
+        let addr = "127.0.0.1:1".parse().unwrap();
+
+        assert!(network.connect(addr).is_err());
+
+        network.listen(addr, handler.clone()).unwrap();
+
+        assert!(network.listen(addr, handler).is_err());
+
+        // the clones share the network
+        let mut client = network.clone().connect(addr).unwrap();
+
+        let events = client.pull(HashMap::new()).unwrap();
+
+        assert_eq!(events.sender_id, peer.id);
+        assert_eq!(events.diff[&peer.id].len(), 2);
+
+        // bodies are only served once announced in a compact diff
+        assert!(client.fetch_txs(vec![tx_ref.clone()]).unwrap().is_empty());
+
+        assert_eq!(client.pull_compact(HashMap::new()).unwrap().lazy, vec![tx_ref.clone()]);
+        assert_eq!(client.fetch_txs(vec![tx_ref; 100]).unwrap().len(), 1);
+
+        network.close(addr);
+
+        assert!(client.pull(HashMap::new()).is_err());
+        assert!(network.connect(addr).is_err());
+    }
+
+    #[test]
+    fn simulator_test() {
+        use simulator::{Partition, SimConfig, Simulator};
+
+        // fault free, every node orders the same transactions
+        let mut sim = Simulator::new(SimConfig::default());
+
+        sim.run(200);
+        sim.assert_prefix_consistent();
+
+        assert!(sim.ordered(0).len() > 0);
+
+        // a seed replays the same run, down to the consensus timestamps
+        let mut replay = Simulator::new(SimConfig::default());
+
+        replay.run(200);
+
+        assert_eq!(replay.ordered(0), sim.ordered(0));
+
+        for seed in 2..5 {
+            let mut sim = Simulator::new(SimConfig {
+                seed,
+                drop_rate: 0.1,
+                duplicate_rate: 0.1,
+                reorder_rate: 0.2,
+                // no side holds a super majority
+                partitions: vec![Partition {
+                    from: 50,
+                    to: 120,
+                    groups: vec![vec![0, 1]],
+                }],
+                ..SimConfig::default()
+            });
+
+            sim.run(150);
+            sim.assert_prefix_consistent();
+
+            let before_heal = sim.ordered(0).len();
+
+            sim.config.heal();
+            sim.run(200);
+            sim.assert_prefix_consistent();
+
+            // consensus resumes once the network heals
+            assert!(sim.ordered(0).len() > before_heal, "Seed {}", seed);
+            assert!(sim.ordered(0).len() as u64 <= sim.submitted());
+        }
+
+        // pulls and pushes both go in pages of two events, some of them lost
+        let mut sim = Simulator::new(SimConfig {
+            seed: 5,
+            page_size: 2,
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+            reorder_rate: 0.2,
+            partitions: vec![Partition {
+                from: 50,
+                to: 120,
+                groups: vec![vec![0, 1]],
+            }],
+            ..SimConfig::default()
+        });
+
+        sim.run(150);
+        sim.assert_prefix_consistent();
+
+        let before_heal = sim.ordered(0).len();
+
+        sim.config.heal();
+        sim.run(300);
+        sim.assert_prefix_consistent();
+
+        assert!(sim.ordered(0).len() > before_heal);
+    }
+
+    #[test]
+    fn byzantine_test() {
+        use events::{MAX_DIFF_BYTES, MAX_DIFF_EVENTS};
+        use simulator::{Byzantine, SimConfig, Simulator, SIM_EPOCH};
+
+        let hour = 3_600_000_000;
+
+        let profiles = vec![
+            Byzantine::ForgeOtherParent,
+            Byzantine::Fork,
+            Byzantine::Withhold(vec![0, 1]),
+            Byzantine::LieTimestamp(hour),
+            Byzantine::LieTimestamp(-hour),
+            Byzantine::Oversized(MAX_DIFF_BYTES + 1),
+            Byzantine::Flood(MAX_DIFF_EVENTS as usize + 1),
+        ];
+
+        // f = 1 < n / 3, on a faulty network that heals
+        for seed in 1..4 {
+            for profile in profiles.iter().cloned() {
+                let mut sim = Simulator::new(SimConfig {
+                    nodes: 4,
+                    seed,
+                    drop_rate: 0.05,
+                    duplicate_rate: 0.05,
+                    reorder_rate: 0.1,
+                    byzantine: vec![(3, profile.clone())],
+                    ..SimConfig::default()
+                });
+
+                sim.run(150);
+                sim.assert_prefix_consistent();
+
+                sim.config.heal();
+                sim.run(250);
+                sim.assert_prefix_consistent();
+
+                // honest timestamps all come from the tick clock, a liar
+                // moves the average of the median and its neighbours by a
+                // third of its skew at most
+                let slack = match profile {
+                    Byzantine::LieTimestamp(skew) => skew.abs() as u64 / 3 + 1,
+                    _ => 0,
+                };
+
+                let (start, end) = (SIM_EPOCH - slack, sim.now() + slack);
+
+                let byzantine_id = sim.hashgraph(3).get_last_decided_peers().self_id;
+
+                for i in sim.honest() {
+                    let hg = sim.hashgraph(i);
+
+                    // no oversized event nor flooding diff was stored
+                    for event in hg.events.all_events() {
+                        let size = event.transactions.iter().map(|tx| tx.len()).sum::<usize>();
+
+                        assert!(size <= MAX_DIFF_BYTES);
+                        assert!(!event.transactions.contains(&b"flood".to_vec()));
+                    }
+
+                    // every profile leaves the honest nodes live
+                    assert!(
+                        sim.ordered(i).len() > 0,
+                        "Seed {}: node {} stalled, {:?}",
+                        seed,
+                        i,
+                        profile
+                    );
+
+                    for tx in sim.ordered(i) {
+                        assert!(tx.timestamp >= start && tx.timestamp <= end, "{:?}", profile);
+                    }
+
+                    // and the proof of a fork reaches all of them
+                    if let Byzantine::Fork = profile {
+                        assert!(hg.forks.contains_key(&byzantine_id), "Seed {}", seed);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn frame_test() {
+        let (hg, _, peers) = create_consensus_hashgraph();
+
+        let joiner = peers.clone().get_peers().keys().cloned().last().unwrap();
+
+        let mut frame = hg.get_last_frame(joiner);
+
+        // a frame cut above the roots, as when the network has purged them
+        for (_, round) in frame.events.iter_mut() {
+            for (_, events) in round.1.iter_mut() {
+                events.remove(&0);
+            }
+        }
+
+        let sent = frame
+            .events
+            .values()
+            .flat_map(|round| round.1.values().flat_map(|events| events.values().cloned()))
+            .collect::<Vec<Event>>();
+
+        let (tx_out, _tx_out_recv) = channel();
+
+        let mut hg2 = Hashgraph::new(get_key(joiner), Arc::new(Mutex::new(tx_out)));
+
+        let (nb_events, _) = hg2.insert_frame(frame, joiner);
+
+        assert_eq!(nb_events, sent.len());
+
+        // the events stay as signed, others accept them from the joiner
+        for event in sent {
+            let inserted = hg2.events.get_event(&event.hash).unwrap();
+
+            assert_eq!(inserted.self_parent, event.self_parent);
+            assert!(inserted.check_hash());
+        }
+    }
+
+    #[test]
+    fn merge_parents_first_test() {
+        let (hg, _, mut peers) = create_consensus_hashgraph();
+
+        let self_id = hg.get_last_decided_peers().self_id;
+
+        peers.self_id = *peers
+            .clone()
+            .get_peers()
+            .keys()
+            .find(|id| **id != self_id)
+            .unwrap();
+
+        let (tx_out, _tx_out_recv) = channel();
+
+        let mut lagging = Hashgraph::new(get_key(peers.self_id), Arc::new(Mutex::new(tx_out)));
+
+        lagging.bootstrap(peers.clone());
+
+        // b1 needs a0, c1 needs b1 and a1 needs c2: whatever the order of the
+        // creators in the diff, one of them comes before a parent
+        let diff = hg.get_events_diff(0, HashMap::new(), 0);
+
+        assert!(!diff.has_more);
+        assert!(lagging.merge_events(peers.self_id, self_id, diff).is_ok());
+
+        for (creator, id) in hg.events.known_events() {
+            assert!(lagging.events.known_events().get(&creator) >= Some(&id));
+        }
+    }
+
+    #[test]
+    fn pagination_test() {
+        let (hg, _, mut peers) = create_consensus_hashgraph();
+
+        let total = hg.events.all_events().len();
+        let self_id = hg.get_last_decided_peers().self_id;
+
+        peers.self_id = *peers
+            .clone()
+            .get_peers()
+            .keys()
+            .find(|id| **id != self_id)
+            .unwrap();
+
+        let (tx_out, _tx_out_recv) = channel();
+
+        let mut lagging = Hashgraph::new(get_key(peers.self_id), Arc::new(Mutex::new(tx_out)));
+
+        lagging.bootstrap(peers.clone());
+
+        let mut pages = 0;
+
+        loop {
+            let diff = hg.get_events_diff(0, lagging.events.known_events(), 4);
+
+            let count = diff.diff.values().fold(0, |c, events| c + events.len());
+
+            assert!(count <= 4);
+            assert!(lagging.check_events_diff(&diff).is_ok());
+
+            // every page only needs the previous ones
+            assert_eq!(lagging.merge_page(&diff), count);
+
+            pages += 1;
+
+            if !diff.has_more {
+                break;
+            }
+
+            // a page is never merged as a whole gossip
+            assert!(lagging.merge_events(peers.self_id, self_id, diff).is_err());
+        }
+
+        assert!(pages >= total / 4);
+        assert_eq!(lagging.events.all_events().len(), total);
+    }
+
+    #[test]
+    fn lazy_txs_test() {
+        let mut peers = Peers::new();
+
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+
+        peers.add(peer1.clone());
+        peers.add(peer2.clone());
+
+        let big1 = "1".repeat(LAZY_TX_BYTES);
+        let big2 = "2".repeat(LAZY_TX_BYTES);
+
+        let to_insert = vec![
+            ("a0".to_string(), "".to_string(), big1.clone(), vec![]),
+            ("b0".to_string(), "".to_string(), "".to_string(), vec![]),
+            ("a1".to_string(), "b0".to_string(), big2.clone(), vec![]),
+            ("b1".to_string(), "a1".to_string(), "small".to_string(), vec![]),
+        ];
+
+        let (hg, _, _) = insert_events_create(to_insert, peers.clone());
+
+        peers.self_id = peer2.id;
+
+        let (tx_out, _tx_out_recv) = channel();
+
+        let mut lagging = Hashgraph::new(get_key(peer2.id), Arc::new(Mutex::new(tx_out)));
+
+        lagging.bootstrap(peers.clone());
+
+        // the client sent the first transaction to both nodes
+        lagging.submit_tx(big1.clone().into_bytes());
+
+        let mut diff = hg.get_compact_events_diff(0, HashMap::new(), 0);
+
+        // only the large bodies are left out, the signature still holds
+        assert_eq!(diff.lazy.len(), 2);
+        assert!(lagging.check_events_diff(&diff).is_ok());
+
+        let missing = lagging.fill_txs(&mut diff);
+
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].id, tx_id(big2.as_bytes()));
+
+        // a body that is not the announced one is refused
+        assert_eq!(diff.fill_txs(vec![(missing[0].clone(), b"forged".to_vec())]), 0);
+
+        // each ref is answered once, however many times it is asked
+        assert_eq!(hg.get_txs(&vec![missing[0].clone(); 100]).len(), 1);
+
+        let bodies = hg.get_txs(&missing);
+
+        assert_eq!(diff.fill_txs(bodies), 1);
+        assert_eq!(diff.drop_incomplete(), 0);
+        assert_eq!(lagging.merge_page(&diff), 4);
+
+        // without the bodies, a0 and a1 are dropped and b1 waits for a1
+        let (tx_out, _tx_out_recv) = channel();
+
+        let mut other = Hashgraph::new(get_key(peer2.id), Arc::new(Mutex::new(tx_out)));
+
+        other.bootstrap(peers);
+
+        let mut diff = hg.get_compact_events_diff(0, HashMap::new(), 0);
+
+        assert_eq!(other.fill_txs(&mut diff).len(), 2);
+        assert_eq!(diff.drop_incomplete(), 2);
+        assert_eq!(other.merge_page(&diff), 1);
+    }
+
+    #[test]
+    fn compact_sync_test() {
+        let network = MemTransport::new();
+
+        let key1 = Key::new_generate().unwrap();
+
+        let config = |port: u16, connect: Option<u16>| NodeConfig {
+            listen_addr: format!("127.0.0.1:{}", port).parse().unwrap(),
+            connect_addr: connect.map(|port| format!("127.0.0.1:{}", port).parse().unwrap()),
+            connect_key: connect.map(|_| key1.get_pub()),
+            compact_gossip: true,
+            ..NodeConfig::default()
+        };
+
+        let mut node1 = Node::new(key1.clone(), config(4101, None));
+
+        node1.set_transport(network.clone());
+
+        let _out1 = node1.run().unwrap();
+
+        // node1 starts listening in its own thread
+        while network.connect("127.0.0.1:4101".parse().unwrap()).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut node2 = Node::new(Key::new_generate().unwrap(), config(4102, Some(4101)));
+
+        node2.set_transport(network.clone());
+
+        let out2 = node2.run().unwrap();
+
+        // sent by hash, node2 fetches the body
+        let big = vec![42; LAZY_TX_BYTES * 4];
+
+        assert!(node1.add_tx(big.clone()));
+
+        loop {
+            let tx = out2.recv_timeout(Duration::from_secs(60)).unwrap();
+
+            if tx.data == big {
+                break;
+            }
+        }
+
+        node1.close();
+        node2.close();
+    }
+
+    #[test]
+    fn mem_transport_test() {
+        let network = MemTransport::new();
+
+        let key1 = Key::new_generate().unwrap();
+
+        let addr = |port: u16| -> SocketAddr { format!("127.0.0.1:{}", port).parse().unwrap() };
+
+        let config = |port: u16, connect: Option<u16>| NodeConfig {
+            listen_addr: addr(port),
+            connect_addr: connect.map(addr),
+            connect_key: connect.map(|_| key1.get_pub()),
+            ..NodeConfig::default()
+        };
+
+        let mut node1 = Node::new(key1.clone(), config(4111, None));
+
+        node1.set_transport(network.clone());
+
+        let out1 = node1.run().unwrap();
+
+        while network.connect(addr(4111)).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // the others join one at a time, through node1
+        let mut nodes = vec![(node1, out1)];
+
+        for port in 4112..4114 {
+            let mut node = Node::new(Key::new_generate().unwrap(), config(port, Some(4111)));
+
+            node.set_transport(network.clone());
+
+            let out = node.run().unwrap();
+
+            // it has joined once it orders transactions
+            let joined = (0..60).any(|i| {
+                assert!(nodes[0].0.add_tx(format!("{} {}", port, i).into_bytes()));
+
+                out.recv_timeout(Duration::from_secs(1)).is_ok()
+            });
+
+            assert!(joined, "Node {} did not join", port);
+
+            nodes.push((node, out));
+        }
+
+        // submitted to the last one, ordered the same way by all
+        let tx = b"to everyone".to_vec();
+
+        assert!(nodes[2].0.add_tx(tx.clone()));
+
+        let ordered = nodes
+            .iter()
+            .map(|&(_, ref out)| loop {
+                let consensus_tx = out.recv_timeout(Duration::from_secs(60)).unwrap();
+
+                if consensus_tx.data == tx {
+                    break (
+                        consensus_tx.event_hash,
+                        consensus_tx.round_received,
+                        consensus_tx.timestamp,
+                    );
+                }
+            })
+            .collect::<Vec<(EventHash, u64, u64)>>();
+
+        assert_eq!(ordered[0], ordered[1]);
+        assert_eq!(ordered[0], ordered[2]);
+
+        // only the closed address stops answering
+        nodes[2].0.close();
+
+        assert!(network.connect(addr(4113)).is_err());
+        assert!(network.connect(addr(4111)).is_ok());
+
+        nodes[0].0.close();
+        nodes[1].0.close();
+    }
+
+    #[test]
+    fn consensus_test() {
+        let (hg, indexes, _) = create_consensus_hashgraph();
+
+        let assert_round = |hash: &str, res: u64| {
+            assert_eq!(
+                hg.events
+                    .get_event(&indexes.get(hash).unwrap().clone().hash)
+                    .unwrap()
+                    .round,
+                res
+            );
+        };
+
+        let assert_famous = |r: u64, hash: &str, res: FamousType| {
+            let round = hg.rounds.get(&r).unwrap().clone();
+
+            assert_eq!(
+                round
+                    .events
+                    .get(&indexes.get(hash).unwrap().clone().hash)
                     .unwrap()
                     .famous,
                 res
@@ -721,6 +2628,10 @@ mod hashgraph_tests {
             assert_famous(r, hash, val);
         }
 
+        for r in 1..=3 {
+            assert!(hg.rounds.get(&r).unwrap().decided);
+        }
+
         let undecideds = vec![
             "b5", "b6", "b7", "b8", "b9", "a6", "a7", "a8", "a9", "c5", "c6", "c7", "c8", "c9",
         ];
@@ -818,17 +2729,21 @@ mod hashgraph_tests {
     */
 
     #[allow(dead_code)]
-    fn create_simple_dyn_hashgraph() -> (Hashgraph, HashMap<String, Event>, Peers, Receiver<Vec<u8>>)
-    {
+    fn create_simple_dyn_hashgraph() -> (
+        Hashgraph,
+        HashMap<String, Event>,
+        Peers,
+        Receiver<ConsensusTransaction>,
+    ) {
         let mut peers = Peers::new();
 
-        let peer1 = Peer::new("127.0.0.1:1".parse().unwrap(), vec![0]);
-        let peer2 = Peer::new("127.0.0.1:2".parse().unwrap(), vec![1]);
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
 
         peers.add(peer1.clone());
         peers.add(peer2.clone());
 
-        let peer3 = Peer::new("127.0.0.1:3".parse().unwrap(), vec![2]);
+        let peer3 = new_peer("127.0.0.1:3");
 
         // (name, other_parent)
         let to_insert = vec![
@@ -1063,9 +2978,9 @@ mod hashgraph_tests {
     fn create_dyn_hashgraph() -> (Hashgraph, HashMap<String, Event>, Peers) {
         let mut peers = Peers::new();
 
-        let peer1 = Peer::new("127.0.0.1:1".parse().unwrap(), vec![0]);
-        let peer2 = Peer::new("127.0.0.1:2".parse().unwrap(), vec![1]);
-        let peer3 = Peer::new("127.0.0.1:3".parse().unwrap(), vec![2]);
+        let peer1 = new_peer("127.0.0.1:1");
+        let peer2 = new_peer("127.0.0.1:2");
+        let peer3 = new_peer("127.0.0.1:3");
 
         peers.add(peer1.clone());
         peers.add(peer2.clone());
@@ -1098,7 +3013,7 @@ mod hashgraph_tests {
     // fn test_dyn_hashgraph() {
     //     let (mut hg, mut indexes, peers) = create_dyn_hashgraph();
 
-    //     let peer4 = Peer::new("127.0.0.1:4".parse().unwrap(), vec![3]);
+    //     let peer4 = new_peer("127.0.0.1:4");
     //     // add peer
     //     hg.add_self_event(vec![], vec![PeerTx::new_join(peer4)]);
 
@@ -0,0 +1,441 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use super::event::{Event, EventHash};
+use super::event_store::EventStore;
+use super::hashgraph::Hashgraph;
+use super::hex::{from_hex, to_hex};
+use super::node::Node;
+use super::receipt::{Receipt, TxId};
+use super::round::FamousType;
+use super::transaction::ConsensusTransaction;
+
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const MAX_CONSENSUS_PAGE: usize = 1000;
+const DEFAULT_WAIT_MS: u64 = 30_000;
+const MAX_CONNECTIONS: usize = 64;
+const IO_TIMEOUT_MS: u64 = 10_000;
+const JSON: &str = "application/json";
+const GRAPHVIZ: &str = "text/vnd.graphviz";
+
+type SharedHashgraph = Arc<RwLock<Hashgraph<Box<EventStore>>>>;
+
+// Minimal HTTP/1.1 server speaking JSON, one thread per connection and at
+// most MAX_CONNECTIONS of them. Slow clients time out after IO_TIMEOUT_MS.
+//
+//   POST /tx                    raw body is the transaction
+//   GET  /tx/{tx_id}            receipt of a submitted transaction
+//   GET  /status
+//   GET  /peers
+//   GET  /events/{hash}
+//   GET  /rounds/{id}
+//   GET  /consensus?from=N&max=M&wait=MS   long-poll of ordered transactions
+//   GET  /dot                   Graphviz view of the retained events, not JSON
+//
+// Binary values (hashes, ids, transactions) are hex encoded.
+pub fn serve(addr: SocketAddr, node: Node, hg: SharedHashgraph) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Http: Cannot listen on {}: {}", addr, e);
+
+            return;
+        }
+    };
+
+    info!("Http: Listening on {}", addr);
+
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Http: {}", e);
+
+                    continue;
+                }
+            };
+
+            let timeout = Some(Duration::from_millis(IO_TIMEOUT_MS));
+
+            if let Err(e) = stream
+                .set_read_timeout(timeout)
+                .and_then(|_| stream.set_write_timeout(timeout))
+            {
+                debug!("Http: {}", e);
+
+                continue;
+            }
+
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+
+                let _ = respond(stream, 503, &error_json("Too many connections"));
+
+                continue;
+            }
+
+            let node = node.clone();
+            let hg = hg.clone();
+            let connections = connections.clone();
+
+            thread::spawn(move || {
+                defer!({
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                });
+
+                if let Err(e) = handle(stream, node, hg) {
+                    debug!("Http: {}", e);
+                }
+            });
+        }
+    });
+}
+
+fn handle(stream: TcpStream, mut node: Node, hg: SharedHashgraph) -> Result<(), String> {
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+
+    let mut request_line = String::new();
+
+    reader
+        .read_line(&mut request_line)
+        .map_err(|e| e.to_string())?;
+
+    let mut parts = request_line.split_whitespace();
+
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("").to_string();
+
+    let mut content_length = 0;
+
+    loop {
+        let mut line = String::new();
+
+        reader.read_line(&mut line).map_err(|e| e.to_string())?;
+
+        let line = line.trim_right();
+
+        if line.is_empty() {
+            break;
+        }
+
+        let mut header = line.splitn(2, ':');
+        let name = header.next().unwrap_or("").trim().to_lowercase();
+        let value = header.next().unwrap_or("").trim();
+
+        if name == "content-length" {
+            content_length = value.parse::<usize>().unwrap_or(0);
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return respond(stream, 413, &error_json("Body too large"));
+    }
+
+    let mut body = vec![0; content_length];
+
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+
+    let (status, content_type, body) = route(&method, &target, body, &mut node, &hg);
+
+    write_response(stream, status, content_type, &body)
+}
+
+fn route(
+    method: &str,
+    target: &str,
+    body: Vec<u8>,
+    node: &mut Node,
+    hg: &SharedHashgraph,
+) -> (u16, &'static str, String) {
+    let mut target = target.splitn(2, '?');
+
+    let path = target.next().unwrap_or("");
+    let query = target.next().unwrap_or("");
+
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+
+    let (status, body) = match (method, segments.as_slice()) {
+        ("GET", ["dot"]) => return (200, GRAPHVIZ, hg.read().unwrap().to_dot()),
+        ("POST", ["tx"]) => match node.submit_tx(body) {
+            Some(tx_id) => (202, json!({ "tx_id": to_hex(&tx_id) })),
+            None => (
+                503,
+                error_json("Transaction refused by the application or mempool full"),
+            ),
+        },
+        ("GET", ["tx", tx_id]) => match from_hex(tx_id).and_then(|id| to_hash(&id)) {
+            Some(tx_id) => receipt(node, &tx_id),
+            None => (400, error_json("Bad transaction id")),
+        },
+        ("GET", ["status"]) => (200, status(hg)),
+        ("GET", ["peers"]) => (200, peers(hg)),
+        ("GET", ["events", hash]) => match from_hex(hash).and_then(|h| to_hash(&h)) {
+            Some(hash) => event(hg, &hash),
+            None => (400, error_json("Bad event hash")),
+        },
+        ("GET", ["rounds", id]) => match id.parse::<u64>() {
+            Ok(id) => round(hg, id),
+            Err(_) => (400, error_json("Bad round id")),
+        },
+        ("GET", ["consensus"]) => consensus(hg, query),
+        _ => (404, error_json("Not found")),
+    };
+
+    (status, JSON, body.to_string())
+}
+
+fn receipt(node: &Node, tx_id: &TxId) -> (u16, Value) {
+    match node.get_receipt(tx_id) {
+        Receipt::Unknown => (404, error_json("Unknown transaction")),
+        Receipt::Pending => (200, json!({ "status": "pending" })),
+        Receipt::Included(event_hash) => (
+            200,
+            json!({ "status": "included", "event": to_hex(&event_hash) }),
+        ),
+        Receipt::Ordered {
+            index,
+            timestamp,
+            round_received,
+        } => (
+            200,
+            json!({
+                "status": "ordered",
+                "index": index,
+                "timestamp": timestamp,
+                "round_received": round_received,
+            }),
+        ),
+    }
+}
+
+fn status(hg: &SharedHashgraph) -> Value {
+    let hg = hg.read().unwrap();
+
+    let last_round = hg.rounds.keys().last().cloned().unwrap_or(0);
+
+    let last_decided_round = hg
+        .rounds
+        .values()
+        .filter(|round| round.decided)
+        .map(|round| round.id)
+        .last()
+        .unwrap_or(0);
+
+    json!({
+        "last_round": last_round,
+        "last_decided_round": last_decided_round,
+        "undecided": hg.events.undecided.len(),
+        "peers": hg.get_last_decided_peers().len(),
+        "consensus_index": hg.consensus_index,
+        "mempool": hg.mempool.len(),
+    })
+}
+
+fn peers(hg: &SharedHashgraph) -> Value {
+    let peers = hg.read().unwrap().get_last_decided_peers();
+    let self_id = peers.self_id;
+
+    Value::Array(
+        peers
+            .get_peers()
+            .values()
+            .map(|peer| {
+                json!({
+                    "id": peer.id,
+                    "address": peer.address.to_string(),
+                    "pub_key": to_hex(&peer.pub_key),
+                    "stake": peer.stake,
+                    "self": peer.id == self_id,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn event(hg: &SharedHashgraph, hash: &EventHash) -> (u16, Value) {
+    match hg.read().unwrap().events.get_event(hash) {
+        Some(event) => (200, event_json(&event)),
+        None => (404, error_json("Unknown event")),
+    }
+}
+
+fn event_json(event: &Event) -> Value {
+    json!({
+        "hash": to_hex(&event.hash),
+        "id": event.id,
+        "creator": event.creator,
+        "self_parent": to_hex(&event.self_parent),
+        "other_parent": to_hex(&event.other_parent),
+        "timestamp": event.timestamp,
+        "round": event.round,
+        "transactions": event
+            .transactions
+            .iter()
+            .map(|tx| to_hex(tx))
+            .collect::<Vec<String>>(),
+        "peer_txs": event
+            .internal_transactions
+            .iter()
+            .map(|tx| json!({ "type": format!("{:?}", tx.tx_type), "peer": tx.peer.id }))
+            .collect::<Vec<Value>>(),
+        "signature": to_hex(&event.signature),
+    })
+}
+
+fn round(hg: &SharedHashgraph, id: u64) -> (u16, Value) {
+    let hg = hg.read().unwrap();
+
+    let round = match hg.rounds.get(&id) {
+        Some(round) => round,
+        None => return (404, error_json("Unknown round")),
+    };
+
+    let events = round
+        .events
+        .values()
+        .map(|e| {
+            let famous = match e.famous {
+                FamousType::True => "true",
+                FamousType::False => "false",
+                FamousType::Undefined => "undefined",
+            };
+
+            json!({
+                "hash": to_hex(&e.hash),
+                "witness": e.witness,
+                "famous": famous,
+                "received": e.received,
+                "timestamp": e.timestamp,
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    (
+        200,
+        json!({
+            "id": round.id,
+            "decided": round.decided,
+            "witnesses": round
+                .witnesses
+                .iter()
+                .map(|hash| to_hex(hash))
+                .collect::<Vec<String>>(),
+            "peers": round.peers.len(),
+            "events": events,
+        }),
+    )
+}
+
+fn consensus(hg: &SharedHashgraph, query: &str) -> (u16, Value) {
+    let param = |name: &str, default: u64| {
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut pair = pair.splitn(2, '=');
+
+                match (pair.next(), pair.next()) {
+                    (Some(key), Some(value)) if key == name => value.parse::<u64>().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or(default)
+    };
+
+    let from = param("from", 0);
+    let max = (param("max", MAX_CONSENSUS_PAGE as u64) as usize).min(MAX_CONSENSUS_PAGE);
+    let wait = Duration::from_millis(param("wait", DEFAULT_WAIT_MS).min(DEFAULT_WAIT_MS));
+
+    // the hashgraph lock must not be held while waiting
+    let consensus_log = hg.read().unwrap().consensus_log.clone();
+
+    match consensus_log.read_from(from, max, wait) {
+        Ok(txs) => {
+            let next = txs.last().map_or(from, |tx| tx.index + 1);
+
+            (
+                200,
+                json!({
+                    "from": from,
+                    "next": next,
+                    "transactions": txs.iter().map(consensus_tx_json).collect::<Vec<Value>>(),
+                }),
+            )
+        }
+        Err(first) => (
+            410,
+            json!({ "error": "Transactions no longer retained", "first": first }),
+        ),
+    }
+}
+
+fn consensus_tx_json(tx: &ConsensusTransaction) -> Value {
+    json!({
+        "index": tx.index,
+        "data": to_hex(&tx.data),
+        "event_hash": to_hex(&tx.event_hash),
+        "creator": tx.creator,
+        "round_received": tx.round_received,
+        "timestamp": tx.timestamp,
+    })
+}
+
+fn respond(stream: TcpStream, status: u16, body: &Value) -> Result<(), String> {
+    write_response(stream, status, JSON, &body.to_string())
+}
+
+fn write_response(
+    mut stream: TcpStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> Result<(), String> {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        _ => "Service Unavailable",
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn error_json(message: &str) -> Value {
+    json!({ "error": message })
+}
+
+fn to_hash(bytes: &[u8]) -> Option<EventHash> {
+    if bytes.len() != 32 {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+
+    hash.copy_from_slice(bytes);
+
+    Some(hash)
+}
@@ -4,6 +4,7 @@ use super::peer::Peer;
 pub enum PeerTxType {
     Join,
     Leave,
+    Stake, // sets the stake of the peer to peer.stake
 }
 
 #[derive(Hash, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -30,4 +31,11 @@ impl PeerTx {
             peer,
         }
     }
+
+    pub fn new_stake(peer: Peer, stake: u64) -> PeerTx {
+        PeerTx {
+            tx_type: PeerTxType::Stake,
+            peer: peer.with_stake(stake),
+        }
+    }
 }
@@ -1,6 +1,23 @@
-use ring::{error, rand, signature};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::Hasher;
+use ring::rand::SecureRandom;
+use ring::{aead, digest, error, pbkdf2, rand, signature};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+// Key files are either the raw PKCS#8 document, or, when a passphrase is
+// given: MAGIC | salt | nonce | AES-256-GCM(PKCS#8)
+const ENCRYPTED_MAGIC: &[u8] = b"HGKEY1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+// PKCS#8 v2 Ed25519 document as ring writes it:
+// PREFIX | seed (32) | MIDDLE | public key (32)
+const PKCS8_PREFIX: &[u8] = &[
+    0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+const PKCS8_MIDDLE: &[u8] = &[0xa1, 0x23, 0x03, 0x21, 0x00];
 
 pub struct Key {
     pub bytes: [u8; 85], // necessary to impl Clone
@@ -19,6 +36,12 @@ impl Clone for Key {
     }
 }
 
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key {{ pub: {:?} }}", self.get_pub())
+    }
+}
+
 impl Key {
     pub fn new_generate() -> Result<Key, error::Unspecified> {
         let rng = rand::SystemRandom::new();
@@ -32,11 +55,150 @@ impl Key {
         })
     }
 
+    // always the same key for a seed, for reproducible simulations
+    pub fn from_seed(seed: &[u8; 32]) -> Result<Key, error::Unspecified> {
+        let key_pair =
+            signature::Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(&seed[..]))?;
+
+        let mut pkcs8 = PKCS8_PREFIX.to_vec();
+
+        pkcs8.extend_from_slice(seed);
+        pkcs8.extend_from_slice(PKCS8_MIDDLE);
+        pkcs8.extend_from_slice(key_pair.public_key_bytes());
+
+        Key::from_pkcs8(&pkcs8)
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Key, error::Unspecified> {
+        if pkcs8.len() != 85 {
+            return Err(error::Unspecified);
+        }
+
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(pkcs8))?;
+
+        let mut bytes = [0; 85];
+
+        bytes.copy_from_slice(pkcs8);
+
+        Ok(Key { bytes, key_pair })
+    }
+
+    pub fn load(path: &Path, passphrase: Option<&str>) -> Result<Key, String> {
+        let mut content = vec![];
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut content))
+            .map_err(|e| format!("Cannot read key file {:?}: {}", path, e))?;
+
+        let encrypted = content.starts_with(ENCRYPTED_MAGIC);
+
+        let pkcs8 = match (encrypted, passphrase) {
+            (true, Some(passphrase)) => Self::decrypt(&content, passphrase)?,
+            (true, None) => return Err(format!("Key file {:?} needs a passphrase", path)),
+            (false, _) => content,
+        };
+
+        Self::from_pkcs8(&pkcs8).map_err(|_| format!("Invalid key file {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path, passphrase: Option<&str>) -> Result<(), String> {
+        let content = match passphrase {
+            Some(passphrase) => self.encrypt(passphrase)?,
+            None => self.bytes.to_vec(),
+        };
+
+        let mut options = OpenOptions::new();
+
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            options.mode(0o600);
+        }
+
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(&content))
+            .map_err(|e| format!("Cannot write key file {:?}: {}", path, e))
+    }
+
+    fn derive_secret(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+        let mut secret = [0; 32];
+
+        pbkdf2::derive(
+            &digest::SHA256,
+            PBKDF2_ITERATIONS,
+            salt,
+            passphrase.as_bytes(),
+            &mut secret,
+        );
+
+        secret
+    }
+
+    fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>, String> {
+        let rng = rand::SystemRandom::new();
+
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|_| "Cannot generate salt".to_string())?;
+
+        let secret = Self::derive_secret(passphrase, &salt);
+
+        let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, &secret)
+            .map_err(|_| "Cannot create sealing key".to_string())?;
+
+        let tag_len = aead::AES_256_GCM.tag_len();
+
+        let mut in_out = self.bytes.to_vec();
+
+        in_out.extend(vec![0; tag_len]);
+
+        let len = aead::seal_in_place(&sealing_key, &nonce, ENCRYPTED_MAGIC, &mut in_out, tag_len)
+            .map_err(|_| "Cannot encrypt key".to_string())?;
+
+        let mut res = ENCRYPTED_MAGIC.to_vec();
+
+        res.extend_from_slice(&salt);
+        res.extend_from_slice(&nonce);
+        res.extend_from_slice(&in_out[..len]);
+
+        Ok(res)
+    }
+
+    fn decrypt(content: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+        let header_len = ENCRYPTED_MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+        if content.len() <= header_len {
+            return Err("Truncated key file".to_string());
+        }
+
+        let salt = &content[ENCRYPTED_MAGIC.len()..ENCRYPTED_MAGIC.len() + SALT_LEN];
+        let nonce = &content[ENCRYPTED_MAGIC.len() + SALT_LEN..header_len];
+
+        let secret = Self::derive_secret(passphrase, salt);
+
+        let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, &secret)
+            .map_err(|_| "Cannot create opening key".to_string())?;
+
+        let mut in_out = content[header_len..].to_vec();
+
+        let pkcs8 = aead::open_in_place(&opening_key, nonce, ENCRYPTED_MAGIC, 0, &mut in_out)
+            .map_err(|_| "Wrong passphrase or corrupted key file".to_string())?;
+
+        Ok(pkcs8.to_vec())
+    }
+
     pub fn get_pub(&self) -> Vec<u8> {
         self.key_pair.public_key_bytes().to_vec()
     }
 
-    pub fn sign(self, msg: &[u8]) -> Vec<u8> {
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
         let sig = self.key_pair.sign(msg);
 
         let res = sig.as_ref();
@@ -56,10 +218,11 @@ impl Key {
         }
     }
 
+    // the first 8 bytes of the SHA-256 of the key, big endian. The same on
+    // every platform and Rust version
     pub fn pub_to_int(pub_key: Vec<u8>) -> u64 {
-        let mut hasher = DefaultHasher::new();
-
-        hasher.write(&pub_key);
-        hasher.finish()
+        digest::digest(&digest::SHA256, &pub_key).as_ref()[..8]
+            .iter()
+            .fold(0, |id, byte| id << 8 | *byte as u64)
     }
 }
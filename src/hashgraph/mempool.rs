@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+
+pub const DEFAULT_MAX_EVENT_TXS: usize = 256;
+pub const DEFAULT_MAX_EVENT_BYTES: usize = 64 * 1024;
+pub const DEFAULT_MAX_POOL_TXS: usize = 10_000;
+pub const DEFAULT_MAX_POOL_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct MempoolConfig {
+    pub max_event_txs: usize,
+    pub max_event_bytes: usize,
+    pub max_pool_txs: usize,
+    pub max_pool_bytes: usize,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> MempoolConfig {
+        MempoolConfig {
+            max_event_txs: DEFAULT_MAX_EVENT_TXS,
+            max_event_bytes: DEFAULT_MAX_EVENT_BYTES,
+            max_pool_txs: DEFAULT_MAX_POOL_TXS,
+            max_pool_bytes: DEFAULT_MAX_POOL_BYTES,
+        }
+    }
+}
+
+// Submitted transactions waiting for the next self event
+#[derive(Clone, Debug, Default)]
+pub struct Mempool {
+    pub config: MempoolConfig,
+    txs: VecDeque<Vec<u8>>,
+    bytes: usize,
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Mempool {
+        Mempool {
+            config,
+            txs: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    // false when the pool is full, the caller should retry later. A tx that
+    // would not fit in any event is refused too.
+    pub fn push(&mut self, tx: Vec<u8>) -> bool {
+        if tx.len() > self.config.max_event_bytes {
+            debug!("Mempool: Tx too big: {} bytes", tx.len());
+
+            return false;
+        }
+
+        if self.txs.len() >= self.config.max_pool_txs
+            || self.bytes + tx.len() > self.config.max_pool_bytes
+        {
+            debug!("Mempool: Full");
+
+            return false;
+        }
+
+        self.bytes += tx.len();
+        self.txs.push_back(tx);
+
+        true
+    }
+
+    // oldest transactions first, within the per event limits
+    pub fn drain_batch(&mut self) -> Vec<Vec<u8>> {
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+
+        while batch.len() < self.config.max_event_txs {
+            let fits = match self.txs.front() {
+                Some(tx) => batch_bytes + tx.len() <= self.config.max_event_bytes,
+                None => false,
+            };
+
+            if !fits {
+                break;
+            }
+
+            let tx = self.txs.pop_front().unwrap();
+
+            batch_bytes += tx.len();
+            self.bytes -= tx.len();
+
+            batch.push(tx);
+        }
+
+        batch
+    }
+
+    // puts back a batch whose event could not be created, keeping the order
+    pub fn requeue(&mut self, batch: Vec<Vec<u8>>) {
+        for tx in batch.into_iter().rev() {
+            self.bytes += tx.len();
+            self.txs.push_front(tx);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+}
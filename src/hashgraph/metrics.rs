@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    // exported as <name>_sum and <name>_count
+    Summary,
+}
+
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+pub const EVENTS_INSERTED: Metric = Metric {
+    name: "hashgraph_events_inserted_total",
+    help: "Events inserted in the hashgraph",
+    kind: Kind::Counter,
+};
+
+pub const EVENTS_REJECTED: Metric = Metric {
+    name: "hashgraph_events_rejected_total",
+    help: "Events refused on insertion, by reason",
+    kind: Kind::Counter,
+};
+
+pub const UNDECIDED_EVENTS: Metric = Metric {
+    name: "hashgraph_undecided_events",
+    help: "Events without a round received yet",
+    kind: Kind::Gauge,
+};
+
+pub const LAST_DECIDED_ROUND: Metric = Metric {
+    name: "hashgraph_last_decided_round",
+    help: "Last round whose witnesses fame is decided",
+    kind: Kind::Gauge,
+};
+
+pub const ROUND_WITNESSES: Metric = Metric {
+    name: "hashgraph_round_witnesses",
+    help: "Witnesses of each retained round",
+    kind: Kind::Gauge,
+};
+
+pub const GOSSIP_PULLS: Metric = Metric {
+    name: "hashgraph_gossip_pulls_total",
+    help: "Pulls sent to each peer, by result",
+    kind: Kind::Counter,
+};
+
+pub const GOSSIP_PUSHES: Metric = Metric {
+    name: "hashgraph_gossip_pushes_total",
+    help: "Pushes sent to each peer, by result",
+    kind: Kind::Counter,
+};
+
+pub const MERGE_EVENTS_SECONDS: Metric = Metric {
+    name: "hashgraph_merge_events_seconds",
+    help: "Time spent in merge_events",
+    kind: Kind::Summary,
+};
+
+pub const CONSENSUS_LATENCY_SECONDS: Metric = Metric {
+    name: "hashgraph_consensus_latency_seconds",
+    help: "Time from the submission of a transaction to its ordering",
+    kind: Kind::Summary,
+};
+
+pub const CACHE_ENTRIES: Metric = Metric {
+    name: "hashgraph_cache_entries",
+    help: "Entries of each hashgraph cache",
+    kind: Kind::Gauge,
+};
+
+pub const PURGED_EVENTS: Metric = Metric {
+    name: "hashgraph_purged_events_total",
+    help: "Events dropped from memory once their round is old enough",
+    kind: Kind::Counter,
+};
+
+pub const PURGED_ROUNDS: Metric = Metric {
+    name: "hashgraph_purged_rounds_total",
+    help: "Rounds purged",
+    kind: Kind::Counter,
+};
+
+pub const LAZY_TXS: Metric = Metric {
+    name: "hashgraph_lazy_txs_total",
+    help: "Bodies left out of received diffs, by how they were filled",
+    kind: Kind::Counter,
+};
+
+#[derive(Debug)]
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    values: BTreeMap<String, (f64, u64)>, // labels -> (value or sum, count)
+}
+
+// The values of one node. Clones share them, each Hashgraph owns its own so
+// that several nodes in a process do not mix their metrics.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+}
+
+impl Metric {
+    pub fn inc(&self, registry: &Registry) {
+        self.inc_with(registry, &[]);
+    }
+
+    pub fn inc_with(&self, registry: &Registry, labels: &[(&str, &str)]) {
+        self.update(registry, labels, |value| value.0 += 1.0);
+    }
+
+    pub fn add(&self, registry: &Registry, n: u64) {
+        self.add_with(registry, &[], n);
+    }
+
+    pub fn add_with(&self, registry: &Registry, labels: &[(&str, &str)], n: u64) {
+        self.update(registry, labels, |value| value.0 += n as f64);
+    }
+
+    pub fn set(&self, registry: &Registry, v: f64) {
+        self.set_with(registry, &[], v);
+    }
+
+    pub fn set_with(&self, registry: &Registry, labels: &[(&str, &str)], v: f64) {
+        self.update(registry, labels, |value| value.0 = v);
+    }
+
+    pub fn observe(&self, registry: &Registry, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+
+        self.update(registry, &[], |value| {
+            value.0 += seconds;
+            value.1 += 1;
+        });
+    }
+
+    // drops a labelled value, like the witnesses of a purged round
+    pub fn remove_with(&self, registry: &Registry, labels: &[(&str, &str)]) {
+        if let Some(family) = registry.families.lock().unwrap().get_mut(self.name) {
+            family.values.remove(&render_labels(labels));
+        }
+    }
+
+    fn update<F: FnOnce(&mut (f64, u64))>(
+        &self,
+        registry: &Registry,
+        labels: &[(&str, &str)],
+        f: F,
+    ) {
+        let mut registry = registry.families.lock().unwrap();
+
+        let family = registry.entry(self.name).or_insert_with(|| Family {
+            help: self.help,
+            kind: self.kind,
+            values: BTreeMap::new(),
+        });
+
+        f(family
+            .values
+            .entry(render_labels(labels))
+            .or_insert((0.0, 0)));
+    }
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<String>>();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+// Prometheus text exposition format
+pub fn render(registry: &Registry) -> String {
+    let registry = registry.families.lock().unwrap();
+
+    let mut out = String::new();
+
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Summary => "summary",
+        };
+
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, family.help, name, kind
+        ));
+
+        for (labels, &(value, count)) in &family.values {
+            if family.kind == Kind::Summary {
+                out.push_str(&format!("{}_sum{} {}\n", name, labels, value));
+                out.push_str(&format!("{}_count{} {}\n", name, labels, count));
+            } else {
+                out.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        }
+    }
+
+    out
+}
+
+// Scrapes are served one at a time, a stalled client holds the next ones at
+// most this long
+const IO_TIMEOUT_MS: u64 = 2_000;
+
+// answers every request with the metrics of `registry`, whatever the path
+pub fn serve(addr: SocketAddr, registry: Registry) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Metrics: Cannot listen on {}: {}", addr, e);
+
+            return;
+        }
+    };
+
+    info!("Metrics: Listening on {}", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            let timeout = Some(Duration::from_millis(IO_TIMEOUT_MS));
+
+            if let Err(e) = stream
+                .set_read_timeout(timeout)
+                .and_then(|_| stream.set_write_timeout(timeout))
+            {
+                debug!("Metrics: {}", e);
+
+                continue;
+            }
+
+            // the request itself is not needed
+            let mut buf = [0; 1024];
+
+            let _ = stream.read(&mut buf);
+
+            let body = render(&registry);
+
+            let res = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+
+            if let Err(e) = res {
+                debug!("Metrics: {}", e);
+            }
+        }
+    });
+}
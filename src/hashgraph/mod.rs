@@ -17,24 +17,51 @@ extern crate rand;
 extern crate ring;
 extern crate serde;
 extern crate serde_bytes;
+#[macro_use]
+extern crate serde_json;
 extern crate untrusted;
 
+mod app;
+mod consensus_log;
+mod dot;
 mod event;
+mod event_log;
+mod event_store;
 mod events;
+mod fork;
 mod hashgraph;
 mod hashgraph_tests;
+mod hex;
+mod http;
 mod internal_txs;
 mod key;
+mod mempool;
+mod metrics;
 // pub mod logger;
 mod node;
 mod peer;
 mod peers;
+mod receipt;
 mod round;
 mod rpc;
+#[cfg(test)]
+mod simulator;
+mod snapshot;
+mod transaction;
+mod transport;
+mod tx_cache;
 
+pub use app::Application;
+pub use consensus_log::Subscription;
+pub use hex::{from_hex, to_hex};
 pub use key::Key;
+pub use mempool::MempoolConfig;
 pub use node::{Node, NodeConfig};
 pub use peer::Peer;
+pub use receipt::{tx_id, Receipt, TxId};
+pub use rpc::HgRpc;
+pub use transaction::ConsensusTransaction;
+pub use transport::{Connection, Handler, MemTransport, TcpTransport, Transport};
 
 #[macro_export]
 macro_rules! trace_time {
@@ -1,25 +1,50 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 use std::{thread, time};
 
 use super::trace_time;
-use event::Event;
-use hashgraph::Hashgraph;
+use app::{AppHandle, Application};
+use consensus_log::Subscription;
+use event_log::EventLog;
+use event_store::{EventStore, FileEventStore, MemEventStore};
+use events::{EventsDiff, Frame, TxRef, LAZY_TX_BYTES};
+use hashgraph::{Hashgraph, DEFAULT_COIN_ROUND_FREQUENCY, PULL_PAGE_SIZE};
+use http;
 use internal_txs::{PeerTx, PeerTxType};
 use key::Key;
+use mempool::{Mempool, MempoolConfig};
+use metrics::{self, Registry};
 use peer::Peer;
 use peers::Peers;
-use round::Round;
-use rpc::HgRpc;
+use receipt::{Receipt, TxId};
+use snapshot::Snapshot;
+use transaction::ConsensusTransaction;
+use transport::{Connection, Handler, TcpTransport, Transport};
 
 #[derive(Clone, Debug)]
 pub struct NodeConfig {
     pub verbose: u8,
     pub listen_addr: SocketAddr,
     pub connect_addr: Option<SocketAddr>,
+    // public key of the node at connect_addr, the only one trusted to send
+    // the first frame
+    pub connect_key: Option<Vec<u8>>,
+    pub coin_round_frequency: u64,
+    // only used when bootstrapping, joiners start with DEFAULT_STAKE
+    pub stake: u64,
+    pub data_dir: Option<PathBuf>,
+    pub restore: Option<PathBuf>,
+    pub store_dir: Option<PathBuf>,
+    pub mempool: MempoolConfig,
+    pub http_addr: Option<SocketAddr>,
+    pub metrics_addr: Option<SocketAddr>,
+    // pulls and pushes without the large transaction bodies the peer may
+    // already have, see EventsDiff::strip_txs
+    pub compact_gossip: bool,
 }
 
 impl Default for NodeConfig {
@@ -28,6 +53,16 @@ impl Default for NodeConfig {
             verbose: 2,
             listen_addr: "127.0.0.1:3000".parse().unwrap(),
             connect_addr: None,
+            connect_key: None,
+            coin_round_frequency: DEFAULT_COIN_ROUND_FREQUENCY,
+            stake: 1,
+            data_dir: None,
+            restore: None,
+            store_dir: None,
+            mempool: MempoolConfig::default(),
+            http_addr: None,
+            metrics_addr: None,
+            compact_gossip: false,
         }
     }
 }
@@ -37,8 +72,10 @@ pub struct Node {
     key: Key,
     pub config: NodeConfig,
     pub peers: Arc<RwLock<Peers>>,
-    pub tx_channel: Option<Arc<Mutex<Sender<Vec<u8>>>>>,
     pub peer_channel: Option<Arc<Mutex<Sender<PeerTx>>>>,
+    app: Option<AppHandle>,
+    hg: Option<Arc<RwLock<Hashgraph<Box<EventStore>>>>>,
+    transport: Arc<Transport>,
 }
 
 impl Default for Node {
@@ -55,42 +92,128 @@ impl Node {
             key,
             config,
             peers: peers.clone(),
-            tx_channel: None,
             peer_channel: None,
+            app: None,
+            hg: None,
+            transport: Arc::new(TcpTransport::new()),
         }
     }
 
-    pub fn run(&mut self) -> Receiver<Vec<u8>> {
+    // must be set before run()
+    pub fn set_application<A: Application + 'static>(&mut self, app: A) {
+        self.app = Some(AppHandle::new(app));
+    }
+
+    // must be set before run(), TCP by default
+    pub fn set_transport<T: Transport + 'static>(&mut self, transport: T) {
+        self.transport = Arc::new(transport);
+    }
+
+    pub fn app_snapshot(&self) -> Option<Vec<u8>> {
+        self.app
+            .as_ref()
+            .map(|app| app.0.lock().unwrap().snapshot())
+    }
+
+    pub fn app_restore(&self, snapshot: &[u8]) {
+        if let Some(ref app) = self.app {
+            app.0.lock().unwrap().restore(snapshot);
+        }
+    }
+
+    // writes the whole consensus state, only available once running
+    pub fn save_snapshot(&self, path: &Path) -> Result<(), String> {
+        match self.hg {
+            Some(ref hg) => hg.read().unwrap().snapshot().save(path),
+            None => Err("Node is not running".to_string()),
+        }
+    }
+
+    // Err if the store, the event log or the snapshot cannot be read,
+    // nothing is started then
+    pub fn run(&mut self) -> Result<Receiver<ConsensusTransaction>, String> {
         let (tx_out, tx_out_receiver) = channel();
-        let (tx_in, tx_in_receiver) = channel();
         let (peer_in, peer_in_receiver) = channel();
 
         self.peer_channel = Some(Arc::new(Mutex::new(peer_in)));
-        self.tx_channel = Some(Arc::new(Mutex::new(tx_in)));
 
-        let mut local_self = self.clone();
+        let self_peer =
+            Peer::new(self.config.listen_addr, self.key.get_pub()).with_stake(self.config.stake);
 
-        let syncing = Arc::new(RwLock::new(true));
-        let syncing2 = syncing.clone();
+        self.peers.write().unwrap().self_id = self_peer.id;
 
-        thread::spawn(move || {
-            let self_peer = Peer::new(local_self.config.listen_addr, local_self.key.get_pub());
+        let store: Box<EventStore> = match self.config.store_dir {
+            Some(ref store_dir) => Box::new(FileEventStore::open(store_dir)?),
+            None => Box::new(MemEventStore::new()),
+        };
 
-            local_self.peers.write().unwrap().self_id = self_peer.id;
+        let mut hashgraph =
+            Hashgraph::with_store(self.key.clone(), Arc::new(Mutex::new(tx_out)), store);
 
-            let hg = Arc::new(RwLock::new(Hashgraph::new(Arc::new(Mutex::new(tx_out)))));
+        hashgraph.coin_round_frequency = self.config.coin_round_frequency;
+        hashgraph.app = self.app.clone();
+        hashgraph.mempool = Mempool::new(self.config.mempool.clone());
 
-            let hg2 = hg.clone();
-            thread::spawn(move || loop {
-                if *syncing2.read().unwrap() {
-                    continue;
-                }
+        let mut recovered = false;
 
-                let tx = tx_in_receiver.recv();
+        if let Some(ref data_dir) = self.config.data_dir {
+            let mut event_log = EventLog::open(data_dir)?;
 
-                hg2.write().unwrap().add_self_event(tx.unwrap(), vec![]);
-            });
+            let entries = event_log.read_all()?;
+
+            if entries.len() > 0 {
+                info!("Recovering {} entries from {:?}", entries.len(), data_dir);
+
+                hashgraph.replay(entries);
+
+                recovered = true;
+            }
+
+            hashgraph.event_log = Some(Arc::new(Mutex::new(event_log)));
+        }
+
+        if let Some(ref path) = self.config.restore {
+            if recovered {
+                warn!("Ignoring snapshot {:?}, recovered from the event log", path);
+            } else {
+                hashgraph.restore(Snapshot::load(path)?);
+
+                info!("Restored from {:?}", path);
+
+                recovered = true;
+            }
+        }
+
+        if recovered {
+            let mut peers = hashgraph.get_last_decided_peers();
+
+            peers.self_id = self_peer.id;
+
+            *self.peers.write().unwrap() = peers;
+
+            // a snapshot taken by another node may hold no event of ours
+            if hashgraph.events.get_last_event_of(self_peer.id).is_none()
+                && !hashgraph.add_root_event(vec![])
+            {
+                warn!("Cannot add a root event, not a member of the restored rounds");
+            }
+        }
+
+        let hg = Arc::new(RwLock::new(hashgraph));
 
+        self.hg = Some(hg.clone());
+
+        let mut local_self = self.clone();
+
+        if let Some(http_addr) = self.config.http_addr {
+            http::serve(http_addr, self.clone(), hg.clone());
+        }
+
+        if let Some(metrics_addr) = self.config.metrics_addr {
+            metrics::serve(metrics_addr, hg.read().unwrap().metrics.clone());
+        }
+
+        thread::spawn(move || {
             let hg3 = hg.clone();
             thread::spawn(move || loop {
                 let tx = peer_in_receiver.recv();
@@ -100,10 +223,17 @@ impl Node {
                     .add_self_event(vec![], vec![tx.unwrap()]);
             });
 
-            if let Some(addr) = local_self.config.connect_addr {
-                *syncing.write().unwrap() = true;
-
-                Node::sync(hg.clone(), addr, self_peer);
+            if recovered {
+                // the log or the snapshot already holds our rounds and events
+            } else if let Some(addr) = local_self.config.connect_addr {
+                Node::sync(
+                    hg.clone(),
+                    &*local_self.transport,
+                    addr,
+                    local_self.config.connect_key.clone(),
+                    self_peer,
+                    local_self.config.compact_gossip,
+                );
             } else {
                 local_self
                     .peers
@@ -116,37 +246,52 @@ impl Node {
                     .bootstrap(local_self.peers.read().unwrap().clone());
 
                 // bootstrap node, we add the peer_tx on the root
-                hg.write().unwrap().insert_event(Event::new(
-                    0,
-                    self_peer.id,
-                    0,
-                    0,
-                    vec![],
-                    vec![PeerTx::new_join(self_peer.clone())],
-                ));
+                hg.write()
+                    .unwrap()
+                    .add_root_event(vec![PeerTx::new_join(self_peer.clone())]);
             }
 
-            *syncing.write().unwrap() = false;
+            let handler = Handler {
+                node: Arc::new(RwLock::new(local_self.clone())),
+                hg: hg.clone(),
+                peers: local_self.peers.clone(),
+                served: Default::default(),
+                pushed: Default::default(),
+            };
 
-            let server = HgRpc::listen_tcp(&local_self.config.listen_addr.to_string());
+            let listen_addr = local_self.config.listen_addr;
 
-            {
-                let mut guard = server.context.lock().unwrap();
-                (*guard).node = Arc::new(RwLock::new(local_self.clone()));
-                (*guard).hg = hg.clone();
-                (*guard).peers = local_self.peers.clone();
+            if let Err(e) = local_self.transport.listen(listen_addr, handler) {
+                error!("Cannot listen on {}: {}", listen_addr, e);
             }
 
             local_self.gossip(hg);
         });
 
-        tx_out_receiver
+        Ok(tx_out_receiver)
     }
 
-    fn sync(hg: Arc<RwLock<Hashgraph>>, addr: SocketAddr, self_peer: Peer) {
-        let mut client = HgRpc::connect_tcp(&addr.to_string()).unwrap();
+    fn sync(
+        hg: Arc<RwLock<Hashgraph<Box<EventStore>>>>,
+        transport: &Transport,
+        addr: SocketAddr,
+        connect_key: Option<Vec<u8>>,
+        self_peer: Peer,
+        compact: bool,
+    ) {
+        // the frame carries its own peers, so it cannot vouch for its sender
+        let connect_key = match connect_key {
+            Some(connect_key) => connect_key,
+            None => {
+                error!("Sync: No public key pinned for {}", addr);
+
+                return;
+            }
+        };
 
-        client.ask_join(self_peer.clone()).unwrap().unwrap();
+        let mut client = transport.connect(addr).unwrap();
+
+        client.ask_join(self_peer.clone()).unwrap();
 
         info!("Syncing...");
 
@@ -163,7 +308,7 @@ impl Node {
                 return;
             }
 
-            frame = res.unwrap().unwrap();
+            frame = res.unwrap();
 
             if frame.events.len() == 0 {
                 debug!("Waiting for acceptation");
@@ -174,42 +319,176 @@ impl Node {
             }
         }
 
+        if let Err(e) = Node::check_frame_sender(&frame, &connect_key) {
+            error!("Sync: {}", e);
+
+            client.close();
+
+            return;
+        }
+
         trace!("Sync: rounds {}", frame.events.len());
 
         trace_time!("Sync");
 
+        let sender_id = frame.sender_id;
+
+        let (nb_events, nb_peers) = hg.write().unwrap().insert_frame(frame, self_peer.id);
+
+        info!("Synced: Events {}, Peers {}", nb_events, nb_peers);
+
+        // the frame stops at the last decided round, but the peers may hold
+        // more recent events of ours if we are restarting. Any of them may be
+        // the only one with our last events, so no event is made before peers
+        // holding with us a super majority of the stake have answered
+        let mut answered = vec![];
+
+        if Node::pull_all(&hg, &mut *client, sender_id, compact) {
+            answered.push(sender_id);
+        }
+
+        client.close();
+
+        loop {
+            let peers = hg.read().unwrap().get_last_decided_peers();
+
+            for peer in peers.clone().get_peers().values() {
+                if peer.id == self_peer.id || answered.contains(&peer.id) {
+                    continue;
+                }
+
+                let mut client = match transport.connect(peer.address) {
+                    Ok(client) => client,
+                    Err(e) => {
+                        debug!("Sync: Cannot reach {}: {}", peer.id, e);
+
+                        continue;
+                    }
+                };
+
+                if Node::pull_all(&hg, &mut *client, peer.id, compact) {
+                    answered.push(peer.id);
+                }
+
+                client.close();
+            }
+
+            if peers.stake_of(&answered) + peers.get_stake(&self_peer.id) >= peers.super_majority {
+                break;
+            }
+
+            warn!(
+                "Sync: Own events confirmed by {} peers only, retrying",
+                answered.len()
+            );
+
+            thread::sleep(time::Duration::from_millis(1000));
+        }
+
         let mut hg = hg.write().unwrap();
 
-        let mut nb_events = 0;
-        let mut nb_peers = 0;
+        match hg.events.get_last_event_of(self_peer.id) {
+            Some(last_own_event) => {
+                info!("Sync: Resuming own events at {}", last_own_event.id + 1);
+            }
+            None => {
+                hg.add_root_event(vec![]);
+            }
+        }
+    }
 
-        for (round_id, round_events) in frame.clone().events {
-            hg.rounds.entry(round_id).or_insert_with(|| {
-                let mut round = Round::new(round_id);
+    // pulls every page `peer_id` has for us, false if it did not answer them
+    // all
+    fn pull_all(
+        hg: &Arc<RwLock<Hashgraph<Box<EventStore>>>>,
+        client: &mut Connection,
+        peer_id: u64,
+        compact: bool,
+    ) -> bool {
+        loop {
+            let known = hg.read().unwrap().events.known_events();
 
-                round.peers = round_events.0.clone();
+            let pull_res = if compact {
+                client.pull_compact(known)
+            } else {
+                client.pull(known)
+            };
 
-                round.peers.self_id = self_peer.id;
+            let mut events = match pull_res {
+                Ok(events) => events,
+                Err(err) => {
+                    error!("Sync: {:?}", err);
 
-                nb_peers = round.peers.len();
+                    return false;
+                }
+            };
 
-                round
-            });
+            if events.sender_id != peer_id {
+                warn!("Sync: Rejected pull from {}", events.sender_id);
+
+                return false;
+            }
+
+            let mut hg = hg.write().unwrap();
+
+            if let Err(e) = hg.check_events_diff(&events) {
+                warn!("Sync: Rejected pull: {}", e);
+
+                return false;
+            }
 
-            for (_, events) in round_events.1 {
-                for (_, event) in events {
-                    hg.insert_event(event.clone());
+            if compact {
+                let missing = hg.fill_txs(&mut events);
 
-                    nb_events += 1;
+                let registry = hg.metrics.clone();
+
+                if let Err(e) = Node::fetch_txs(client, &mut events, missing, &registry) {
+                    error!("Sync: {:?}", e);
+
+                    return false;
                 }
             }
+
+            let merged = hg.merge_page(&events);
+
+            if !events.has_more {
+                return true;
+            }
+
+            // a page we cannot use would be served again forever
+            if merged == 0 {
+                warn!("Sync: Stalled pull from {}", peer_id);
+
+                return false;
+            }
         }
+    }
 
-        client.close();
+    // the frame must be signed with the pinned key, by a member of the
+    // frame's last round registered under that same key
+    fn check_frame_sender(frame: &Frame, connect_key: &[u8]) -> Result<(), String> {
+        if frame.sender_id != Key::pub_to_int(connect_key.to_vec()) {
+            return Err(format!("Unexpected frame sender {}", frame.sender_id));
+        }
 
-        info!("Synced: Events {}, Peers {}", nb_events, nb_peers);
+        if !frame.verify(connect_key) {
+            return Err(format!("Bad frame signature from {}", frame.sender_id));
+        }
 
-        hg.insert_event(Event::new(0, self_peer.id, 0, 0, vec![], vec![]));
+        let sender = frame
+            .events
+            .values()
+            .last()
+            .and_then(|round| round.0.clone().get_by_id(frame.sender_id));
+
+        match sender {
+            Some(ref sender) if sender.pub_key == connect_key => Ok(()),
+            Some(_) => Err(format!(
+                "Frame sender {} under another key",
+                frame.sender_id
+            )),
+            None => Err(format!("Unknown frame sender {}", frame.sender_id)),
+        }
     }
 
     pub fn peer_join(&mut self, peer: Peer) {
@@ -236,26 +515,79 @@ impl Node {
         });
     }
 
-    pub fn add_tx(&mut self, tx: Vec<u8>) {
-        self.tx_channel.clone().map(|mutex| {
-            mutex.lock().unwrap().send(tx).unwrap();
+    // our vote for the stake of `peer`, applied once peers holding a super
+    // majority of the stake voted the same
+    pub fn peer_stake(&mut self, peer: Peer, stake: u64) {
+        self.peer_channel.clone().map(|mutex| {
+            mutex
+                .lock()
+                .unwrap()
+                .send(PeerTx::new_stake(peer, stake))
+                .unwrap();
 
             mutex
         });
     }
 
+    // returns false if the application refused the transaction or if the
+    // mempool is full, in which case it can be submitted again later
+    pub fn add_tx(&mut self, tx: Vec<u8>) -> bool {
+        self.submit_tx(tx).is_some()
+    }
+
+    // same as add_tx, with the id to follow the transaction by
+    pub fn submit_tx(&mut self, tx: Vec<u8>) -> Option<TxId> {
+        if let Some(ref app) = self.app {
+            if !app.0.lock().unwrap().check_tx(&tx) {
+                debug!("Submit tx: Rejected by the application");
+
+                return None;
+            }
+        }
+
+        match self.hg {
+            Some(ref hg) => hg.write().unwrap().submit_tx(tx),
+            None => {
+                warn!("Submit tx: Node is not running");
+
+                None
+            }
+        }
+    }
+
+    // ordered transactions from the consensus index `from`, None if the node
+    // is not running
+    pub fn subscribe(&self, from: u64) -> Option<Subscription> {
+        self.hg
+            .as_ref()
+            .map(|hg| hg.read().unwrap().consensus_log.subscribe(from))
+    }
+
+    pub fn get_receipt(&self, tx_id: &TxId) -> Receipt {
+        match self.hg {
+            Some(ref hg) => hg.read().unwrap().receipts.get(tx_id),
+            None => Receipt::Unknown,
+        }
+    }
+
     pub fn close(&self) {
-        HgRpc::Duplex::close();
+        self.transport.close(self.config.listen_addr);
     }
 
-    pub fn gossip(&mut self, _hg: Arc<RwLock<Hashgraph>>) {
-        let mut clients: HashMap<u64, HgRpc::Client<rsrpc::TcpTransport>> = HashMap::new();
+    pub fn gossip(&mut self, _hg: Arc<RwLock<Hashgraph<Box<EventStore>>>>) {
+        let mut clients: HashMap<u64, Box<Connection>> = HashMap::new();
         let mut last_seen: HashMap<u64, SystemTime> = HashMap::new();
 
         loop {
-            let peer = match _hg.read().unwrap().get_last_decided_peers().get_random() {
+            // the read guard must be released before flushing
+            let peer = _hg.read().unwrap().get_last_decided_peers().get_random();
+
+            let peer = match peer {
                 Some(p) => p,
                 None => {
+                    // alone, nobody to sync with
+                    _hg.write().unwrap().flush_mempool();
+
                     thread::sleep(time::Duration::from_millis(1000));
 
                     continue;
@@ -264,15 +596,12 @@ impl Node {
 
             trace_time!("Gossip");
 
-            let mut client = {
-                let client = clients.get(&peer.id);
-
-                if client.is_some() {
-                    client.unwrap().clone()
-                } else {
-                    let c = HgRpc::connect_tcp(&peer.address.to_string());
-
-                    if let Err(e) = c {
+            if !clients.contains_key(&peer.id) {
+                match self.transport.connect(peer.address) {
+                    Ok(client) => {
+                        clients.insert(peer.id, client);
+                    }
+                    Err(e) => {
                         debug!("Error connect: {:?} {}", peer.address, e);
                         // send leave tx
 
@@ -284,64 +613,180 @@ impl Node {
 
                         continue;
                     }
+                }
+            }
 
-                    let c = c.unwrap();
+            let compact = self.config.compact_gossip;
 
-                    c
+            let failed = {
+                let client = clients.get_mut(&peer.id).unwrap();
+
+                let res = Node::gossip_with(&_hg, &mut **client, &peer, compact);
+
+                if let Err(ref e) = res {
+                    error!("{}", e);
+
+                    client.close();
                 }
+
+                res.is_err()
             };
 
-            clients.insert(peer.id, client.clone());
+            if failed {
+                clients.remove(&peer.id);
 
-            let self_id = _hg.read().unwrap().get_last_decided_peers().self_id;
+                continue;
+            }
 
-            let hg = _hg.clone();
+            last_seen.entry(peer.id).or_insert(SystemTime::now());
+        }
+    }
 
+    // pulls from `peer` until up to date then pushes once, Err if the
+    // connection failed
+    fn gossip_with(
+        hg: &Arc<RwLock<Hashgraph<Box<EventStore>>>>,
+        client: &mut Connection,
+        peer: &Peer,
+        compact: bool,
+    ) -> Result<(), String> {
+        let self_id = hg.read().unwrap().get_last_decided_peers().self_id;
+        let registry = hg.read().unwrap().metrics.clone();
+
+        let peer_label = peer.id.to_string();
+
+        let pulled = |result| {
+            metrics::GOSSIP_PULLS.inc_with(&registry, &[("peer", &peer_label), ("result", result)])
+        };
+
+        // pages are merged as they come, the last one goes through
+        // merge_events to answer with a push
+        let events = loop {
             let known = hg.read().unwrap().events.known_events();
 
-            let pull_res = client.pull(known);
+            let pull_res = if compact {
+                client.pull_compact(known)
+            } else {
+                client.pull(known)
+            };
 
-            if let Err(err) = pull_res {
-                error!("{:?}", err);
+            let mut events = match pull_res {
+                Ok(events) => events,
+                Err(e) => {
+                    pulled("error");
 
-                client.close();
+                    return Err(e);
+                }
+            };
 
-                clients.remove(&peer.id);
+            if events.sender_id != peer.id {
+                warn!(
+                    "Rejected pull: sender {} is not {}",
+                    events.sender_id, peer.id
+                );
 
-                continue;
+                pulled("rejected");
+
+                return Ok(());
             }
 
-            let events = pull_res.unwrap().unwrap();
+            if let Err(e) = hg.read().unwrap().check_events_diff(&events) {
+                warn!("Rejected pull: {}", e);
+
+                pulled("rejected");
+
+                return Ok(());
+            }
+
+            pulled("ok");
+
+            if compact {
+                let missing = hg.read().unwrap().fill_txs(&mut events);
+
+                Node::fetch_txs(client, &mut events, missing, &registry)?;
+            }
 
             trace!(
                 "Events from pull {:?}",
                 events.diff.iter().fold(0, |c, v| c + v.1.len())
             );
 
-            let events_diff = hg.write().unwrap().merge_events(self_id, peer.id, events);
+            if !events.has_more {
+                break events;
+            }
 
-            if events_diff.is_err() {
-                continue;
+            // the peer would send the same page again
+            if hg.write().unwrap().merge_page(&events) == 0 {
+                warn!("Stalled pull from {}", peer.id);
+
+                return Ok(());
             }
+        };
+
+        let mut peer_known = events.known.clone();
+
+        let mut events_diff = match hg.write().unwrap().merge_events(self_id, peer.id, events) {
+            Ok(events_diff) => events_diff,
+            Err(_) => return Ok(()),
+        };
 
-            let events_diff = events_diff.unwrap();
+        // a page per push, until the peer has everything we had
+        loop {
+            peer_known = events_diff.known_after(peer_known);
+
+            let has_more = events_diff.has_more;
+
+            if compact {
+                events_diff.strip_txs(LAZY_TX_BYTES);
+            }
 
             trace!(
                 "Events to push {:?}",
                 events_diff.diff.iter().fold(0, |c, v| c + v.1.len())
             );
 
-            if let Err(e) = client.push(events_diff) {
-                error!("{:?}", e);
+            let push_res = client.push(events_diff);
 
-                client.close();
+            let result = if push_res.is_ok() { "ok" } else { "error" };
 
-                clients.remove(&peer.id);
+            metrics::GOSSIP_PUSHES
+                .inc_with(&registry, &[("peer", &peer_label), ("result", result)]);
 
-                continue;
+            push_res?;
+
+            if !has_more {
+                return Ok(());
             }
 
-            last_seen.entry(peer.id).or_insert(SystemTime::now());
+            events_diff =
+                hg.read()
+                    .unwrap()
+                    .get_events_diff(peer.id, peer_known.clone(), PULL_PAGE_SIZE);
+
+            if events_diff.diff.is_empty() {
+                return Ok(());
+            }
         }
     }
+
+    // bodies left out of a compact pull and not found here, from the peer.
+    // The events still missing one wait for a later pull
+    fn fetch_txs(
+        client: &mut Connection,
+        events: &mut EventsDiff,
+        missing: Vec<TxRef>,
+        registry: &Registry,
+    ) -> Result<(), String> {
+        if !missing.is_empty() {
+            let filled = events.fill_txs(client.fetch_txs(missing)?);
+
+            metrics::LAZY_TXS.add_with(registry, &[("source", "fetched")], filled as u64);
+        }
+
+        metrics::LAZY_TXS.add_with(registry, &[("source", "missing")], events.lazy.len() as u64);
+
+        events.drop_incomplete();
+
+        Ok(())
+    }
 }
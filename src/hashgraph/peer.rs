@@ -3,11 +3,16 @@ use std::net::SocketAddr;
 
 use super::key::Key;
 
+// stake of a joining peer, only the bootstrap peers and Stake transactions
+// endorsed by a super majority set another one
+pub const DEFAULT_STAKE: u64 = 1;
+
 #[derive(Hash, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Peer {
     pub id: u64,
     pub address: SocketAddr,
     pub pub_key: Vec<u8>,
+    pub stake: u64,
 }
 
 impl Peer {
@@ -16,12 +21,23 @@ impl Peer {
             id: Key::pub_to_int(pub_key.clone()),
             address,
             pub_key,
+            stake: DEFAULT_STAKE,
         }
     }
+
+    pub fn with_stake(mut self, stake: u64) -> Peer {
+        self.stake = stake;
+
+        self
+    }
 }
 
 impl Display for Peer {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "Id: {}, Address: {}", self.id, self.address)
+        write!(
+            f,
+            "Id: {}, Address: {}, Stake: {}",
+            self.id, self.address, self.stake
+        )
     }
 }
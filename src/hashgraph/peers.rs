@@ -7,9 +7,12 @@ use super::peer::Peer;
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Peers {
     pub self_id: u64,
-    pub super_majority: u64,
+    pub super_majority: u64, // in stake
+    pub total_stake: u64,
     peers: BTreeMap<u64, Peer>,
     last_peer: u64,
+    // (peer, stake) -> peers that asked for it, see endorse_stake
+    stake_votes: BTreeMap<(u64, u64), Vec<u64>>,
 }
 
 impl Peers {
@@ -17,8 +20,10 @@ impl Peers {
         Peers {
             self_id: 0,
             super_majority: 0,
+            total_stake: 0,
             peers: BTreeMap::new(),
             last_peer: 0,
+            stake_votes: BTreeMap::new(),
         }
     }
 
@@ -42,7 +47,7 @@ impl Peers {
             self.peers.len(),
         );
 
-        self.super_majority = (2 * self.peers.len() / 3 + 1) as u64;
+        self.update_stake();
     }
 
     pub fn remove(&mut self, peer: Peer) {
@@ -54,7 +59,85 @@ impl Peers {
 
         info!("Remove peer -> {} Nb: {}", peer.clone(), self.peers.len(),);
 
-        self.super_majority = (2 * self.peers.len() / 3 + 1) as u64;
+        self.update_stake();
+    }
+
+    pub fn set_stake(&mut self, id: u64, stake: u64) {
+        match self.peers.get_mut(&id) {
+            Some(peer) => peer.stake = stake,
+            None => return,
+        };
+
+        info!("Set stake -> {} Stake: {}", id, stake);
+
+        self.update_stake();
+    }
+
+    // `endorser` asks to set the stake of `id`. Only applied once peers
+    // holding a super majority of the stake asked for the same, returns
+    // whether it was
+    pub fn endorse_stake(&mut self, endorser: u64, id: u64, stake: u64) -> bool {
+        if self.get_stake(&endorser) == 0 || self.peers.get(&id).is_none() {
+            return false;
+        }
+
+        let endorsers = {
+            let endorsers = self.stake_votes.entry((id, stake)).or_insert_with(Vec::new);
+
+            if !endorsers.contains(&endorser) {
+                endorsers.push(endorser);
+            }
+
+            endorsers.clone()
+        };
+
+        if self.stake_of(&endorsers) < self.super_majority {
+            return false;
+        }
+
+        // the other pending stakes of this peer are outdated
+        let outdated = self
+            .stake_votes
+            .keys()
+            .filter(|(peer_id, _)| *peer_id == id)
+            .cloned()
+            .collect::<Vec<(u64, u64)>>();
+
+        for key in outdated {
+            self.stake_votes.remove(&key);
+        }
+
+        self.set_stake(id, stake);
+
+        true
+    }
+
+    pub fn get_stake(&self, id: &u64) -> u64 {
+        self.peers.get(id).map_or(0, |peer| peer.stake)
+    }
+
+    // total stake of the given peers, each counted once
+    pub fn stake_of(&self, ids: &Vec<u64>) -> u64 {
+        let mut ids = ids.clone();
+
+        ids.sort();
+        ids.dedup();
+
+        ids.iter()
+            .fold(0, |total: u64, id| total.saturating_add(self.get_stake(id)))
+    }
+
+    fn update_stake(&mut self) {
+        self.total_stake = self
+            .peers
+            .values()
+            .fold(0, |total: u64, peer| total.saturating_add(peer.stake));
+
+        // 2 * total / 3 + 1, without overflowing
+        let third = self.total_stake / 3;
+        let rest = self.total_stake % 3;
+
+        self.super_majority = 2 * third + 2 * rest / 3 + 1;
     }
 
     pub fn get_by_id(self, id: u64) -> Option<Peer> {
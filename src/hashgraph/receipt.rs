@@ -0,0 +1,218 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bincode;
+use ring::digest;
+
+use super::event::EventHash;
+
+pub type TxId = [u8; 32];
+
+pub const DEFAULT_MAX_RECEIPTS: usize = 100_000;
+
+// SHA-256 of the transaction bytes, identical transactions share their id
+pub fn tx_id(tx: &[u8]) -> TxId {
+    let mut id = [0; 32];
+
+    id.copy_from_slice(digest::digest(&digest::SHA256, tx).as_ref());
+
+    id
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Receipt {
+    // never submitted here, or forgotten since
+    Unknown,
+    // waiting in the mempool
+    Pending,
+    // part of an event, not ordered yet
+    Included(EventHash),
+    // final
+    Ordered {
+        index: u64,
+        timestamp: u64,
+        round_received: u64,
+    },
+}
+
+// One receipt per submission, even of identical transactions
+#[derive(Clone, Debug)]
+struct Submission {
+    receipt: Receipt,
+    tx_id: TxId,
+    at: Instant,
+}
+
+// Status of the transactions submitted to this node. Only the last
+// `capacity` submissions are remembered.
+//
+// A submission gets its own id, the SHA-256 of a sequence number and the
+// transaction. It is only marked by the self event that carries it, and then
+// only ordered with that event, never by the same transaction submitted
+// elsewhere.
+#[derive(Clone, Debug)]
+pub struct Receipts {
+    capacity: usize,
+    seq: u64,
+    by_id: HashMap<TxId, Submission>,
+    order: VecDeque<TxId>,
+    // submissions in the mempool, by tx_id, oldest first
+    waiting: HashMap<TxId, VecDeque<TxId>>,
+    // submissions in a self event, by event and tx_id
+    included: HashMap<(EventHash, TxId), VecDeque<TxId>>,
+}
+
+impl Default for Receipts {
+    fn default() -> Receipts {
+        Receipts::new(DEFAULT_MAX_RECEIPTS)
+    }
+}
+
+impl Receipts {
+    pub fn new(capacity: usize) -> Receipts {
+        // ids from before a restart are not handed out again
+        let seq = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() << 32)
+            .unwrap_or(0);
+
+        Receipts {
+            capacity,
+            seq,
+            by_id: HashMap::new(),
+            order: VecDeque::new(),
+            waiting: HashMap::new(),
+            included: HashMap::new(),
+        }
+    }
+
+    // a new submission of the transaction `tx_id`, returns its receipt id
+    pub fn pending(&mut self, tx_id: TxId) -> TxId {
+        let id = self.next_id(&tx_id);
+
+        self.by_id.insert(
+            id,
+            Submission {
+                receipt: Receipt::Pending,
+                tx_id,
+                at: Instant::now(),
+            },
+        );
+
+        self.waiting
+            .entry(tx_id)
+            .or_insert_with(VecDeque::new)
+            .push_back(id);
+
+        self.order.push_back(id);
+
+        while self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+
+            self.forget(&oldest);
+        }
+
+        id
+    }
+
+    // the next two only update transactions submitted here, the oldest
+    // submission first
+    pub fn included(&mut self, tx_id: &TxId, event_hash: EventHash) {
+        let id = match take(&mut self.waiting, tx_id) {
+            Some(id) => id,
+            None => return,
+        };
+
+        if let Some(submission) = self.by_id.get_mut(&id) {
+            submission.receipt = Receipt::Included(event_hash);
+        }
+
+        self.included
+            .entry((event_hash, *tx_id))
+            .or_insert_with(VecDeque::new)
+            .push_back(id);
+    }
+
+    // returns the time since the submission
+    pub fn ordered(
+        &mut self,
+        tx_id: &TxId,
+        event_hash: &EventHash,
+        index: u64,
+        timestamp: u64,
+        round_received: u64,
+    ) -> Option<Duration> {
+        let id = take(&mut self.included, &(*event_hash, *tx_id))?;
+
+        let submission = self.by_id.get_mut(&id)?;
+
+        submission.receipt = Receipt::Ordered {
+            index,
+            timestamp,
+            round_received,
+        };
+
+        Some(submission.at.elapsed())
+    }
+
+    pub fn get(&self, id: &TxId) -> Receipt {
+        self.by_id
+            .get(id)
+            .map(|submission| submission.receipt.clone())
+            .unwrap_or(Receipt::Unknown)
+    }
+
+    fn next_id(&mut self, hash: &TxId) -> TxId {
+        let bytes = bincode::serialize(&(self.seq, hash)).unwrap();
+
+        self.seq += 1;
+
+        tx_id(&bytes)
+    }
+
+    fn forget(&mut self, id: &TxId) {
+        let submission = match self.by_id.remove(id) {
+            Some(submission) => submission,
+            None => return,
+        };
+
+        match submission.receipt {
+            Receipt::Pending => remove(&mut self.waiting, &submission.tx_id, id),
+            Receipt::Included(event_hash) => {
+                remove(&mut self.included, &(event_hash, submission.tx_id), id)
+            }
+            _ => (),
+        }
+    }
+}
+
+// pops the oldest id of a queue, dropping it once empty
+fn take<K: Hash + Eq>(queues: &mut HashMap<K, VecDeque<TxId>>, key: &K) -> Option<TxId> {
+    let (id, empty) = {
+        let queue = queues.get_mut(key)?;
+
+        (queue.pop_front(), queue.is_empty())
+    };
+
+    if empty {
+        queues.remove(key);
+    }
+
+    id
+}
+
+fn remove<K: Hash + Eq>(queues: &mut HashMap<K, VecDeque<TxId>>, key: &K, id: &TxId) {
+    let empty = match queues.get_mut(key) {
+        Some(queue) => {
+            queue.retain(|other| other != id);
+
+            queue.is_empty()
+        }
+        None => return,
+    };
+
+    if empty {
+        queues.remove(key);
+    }
+}
@@ -3,16 +3,16 @@ use std::collections::HashMap;
 use super::event::{Event, EventHash};
 use super::peers::Peers;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FamousType {
     True,
     False,
     Undefined,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RoundEvent {
-    pub hash: u64,
+    pub hash: EventHash,
     pub witness: bool,
     pub famous: FamousType,
     pub received: u64,
@@ -33,7 +33,7 @@ impl RoundEvent {
     }
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Round {
     pub id: u64,
     pub events: HashMap<EventHash, RoundEvent>,
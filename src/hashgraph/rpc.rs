@@ -1,76 +1,54 @@
 use std::collections::HashMap;
-use std::sync::RwLock;
 
 use super::event::EventCreator;
-use super::events::{EventsDiff, Frame};
-use super::hashgraph::Hashgraph;
-use super::internal_txs::PeerTx;
-use super::node::Node;
+use super::events::{EventsDiff, Frame, TxRef};
 use super::peer::Peer;
-use super::peers::Peers;
+use super::receipt::{Receipt, TxId};
+use super::transport::Handler;
 
 service! {
   HgRpc {
-    let node: Arc<super::RwLock<super::Node>>;
-    let hg: Arc<super::RwLock<super::Hashgraph>>;
-    let peers: Arc<super::RwLock<super::Peers>>;
+    let handler: super::Handler;
 
     fn fast_sync(&mut self, peer_id: u64) -> super::Frame {
-      self.hg.read().unwrap().get_last_frame(peer_id)
+      self.handler.fast_sync(peer_id)
     }
 
     fn pull(&mut self, known: super::HashMap<super::EventCreator, u64>) -> super::EventsDiff {
-      trace!("RPC: Got events to pull {:?}", known);
-
-      self.hg.read().unwrap().events.events_diff(known, 16)
+      self.handler.pull(known)
     }
 
-    fn push(&mut self, events: super::EventsDiff) -> bool {
-      trace!(
-          "RPC: Got events to push {:?}",
-          events.diff.iter().fold(0, |c, v| c + v.1.len())
-      );
-
-      let peers = self.hg.read().unwrap().get_last_decided_peers();
+    // same as pull, without the large transaction bodies
+    fn pull_compact(&mut self, known: super::HashMap<super::EventCreator, u64>) -> super::EventsDiff {
+      self.handler.pull_compact(known)
+    }
 
-      let self_id = peers.clone().self_id;
-      let peer = peers.clone().get_by_id(events.sender_id);
+    // the bodies left out of a compact pull
+    fn fetch_txs(&mut self, tx_refs: Vec<super::TxRef>) -> Vec<(super::TxRef, Vec<u8>)> {
+      self.handler.fetch_txs(tx_refs)
+    }
 
-      let id = if let Some(p) = peer {
-        p.id
-      } else {
-        warn!("UNKNOWN PEER, {:?}", events.sender_id);
-        0
-      };
+    fn push(&mut self, events: super::EventsDiff) -> bool {
+      self.handler.push(events)
+    }
 
+    // for clients, None if the transaction was refused
+    fn submit_tx(&mut self, tx: Vec<u8>) -> Option<super::TxId> {
+      self.handler.node.write().unwrap().submit_tx(tx)
+    }
 
-      self.hg.write().unwrap().merge_events(self_id, id, events).unwrap();
+    fn get_receipt(&mut self, tx_id: super::TxId) -> super::Receipt {
+      self.handler.node.read().unwrap().get_receipt(&tx_id)
+    }
 
-      true
+    // Graphviz view of the retained events, for debugging
+    fn get_dot(&mut self) -> String {
+      self.handler.hg.read().unwrap().to_dot()
     }
 
     // you are asked to add a new peer. Answer with own pub_key
-    fn ask_join(&mut self, peer: super::Peer) -> bool{
-
-      if self.hg.read().unwrap().get_last_decided_peers().len() == 1 {
-          let mut  hg = self.hg.write().unwrap();
-
-          hg.add_self_event(vec![], vec![super::PeerTx::new_join(peer)]);
-
-          // force consensus
-          hg.add_self_event(vec![], vec![]);
-          hg.add_self_event(vec![], vec![]);
-          hg.add_self_event(vec![], vec![]);
-          hg.add_self_event(vec![], vec![]);
-          hg.add_self_event(vec![], vec![]);
-          hg.add_self_event(vec![], vec![]);
-
-          return true;
-      }
-
-      self.node.write().unwrap().peer_join(peer);
-
-      true
+    fn ask_join(&mut self, peer: super::Peer) -> bool {
+      self.handler.ask_join(peer)
     }
   }
 }
@@ -0,0 +1,590 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use event::{Event, EventCreator, EMPTY_HASH};
+use events::EventsDiff;
+use hashgraph::{Hashgraph, PULL_PAGE_SIZE};
+use key::Key;
+use peer::Peer;
+use peers::Peers;
+use transaction::ConsensusTransaction;
+
+// Nodes in [from, to) ticks can only reach the nodes of their own group.
+// Nodes not listed form one more group.
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub from: u64,
+    pub to: u64,
+    pub groups: Vec<Vec<usize>>,
+}
+
+// Malicious behaviours of a simulated node. With less than a third of them,
+// the honest nodes are expected to:
+//
+//   ForgeOtherParent  stay safe and live, the forged events are never inserted
+//                     as their other-parent cannot be found
+//   Fork              stay safe and live, and all record a fork proof
+//                     against the forker
+//   Withhold          stay safe and live, the others relay the events
+//   LieTimestamp      stay safe and live, consensus timestamps stay within
+//                     a third of the skew of the honest timestamps
+//   Oversized         stay safe and live, diffs above MAX_DIFF_BYTES are
+//                     refused whole
+//   Flood             stay safe and live, diffs above MAX_DIFF_EVENTS are
+//                     refused whole
+//
+// byzantine_test checks each of these, over several seeds and network faults.
+#[derive(Clone, Debug)]
+pub enum Byzantine {
+    // adds to its diffs a signed event whose other-parent does not exist
+    ForgeOtherParent,
+    // adds to its diffs a second signed version of its last event
+    Fork,
+    // never answers nor pushes to these nodes
+    Withhold(Vec<usize>),
+    // shifts the timestamp of its events, in microseconds
+    LieTimestamp(i64),
+    // adds to its diffs an event carrying that many bytes of transactions
+    Oversized(usize),
+    // adds to its diffs that many small events, chained after its last one
+    Flood(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    pub nodes: usize,
+    pub seed: u32,
+    // gossips started each tick, each one is a pull then a push
+    pub gossips_per_tick: usize,
+    // events per pull answer, a gossip pulls pages until up to date
+    pub page_size: u64,
+    // delivery delay of every message, in ticks
+    pub min_delay: u64,
+    pub max_delay: u64,
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    // a reordered message waits max_delay more, so later ones overtake it
+    pub reorder_rate: f64,
+    // chance for each tick to submit a transaction to a random node
+    pub tx_rate: f64,
+    pub partitions: Vec<Partition>,
+    // node index -> behaviour, the others are honest
+    pub byzantine: Vec<(usize, Byzantine)>,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            nodes: 4,
+            seed: 1,
+            gossips_per_tick: 1,
+            page_size: PULL_PAGE_SIZE,
+            min_delay: 1,
+            max_delay: 3,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            tx_rate: 0.5,
+            partitions: vec![],
+            byzantine: vec![],
+        }
+    }
+}
+
+impl SimConfig {
+    // no more network faults from now on, to check that consensus resumes.
+    // Byzantine nodes stay so
+    pub fn heal(&mut self) {
+        self.drop_rate = 0.0;
+        self.duplicate_rate = 0.0;
+        self.reorder_rate = 0.0;
+        self.partitions = vec![];
+    }
+}
+
+#[derive(Clone)]
+enum Message {
+    Pull(HashMap<EventCreator, u64>),
+    PullResponse(EventsDiff),
+    Push(EventsDiff),
+}
+
+struct Envelope {
+    from: usize,
+    to: usize,
+    message: Message,
+}
+
+struct SimNode {
+    hg: Hashgraph,
+    key: Key,
+    behaviour: Option<Byzantine>,
+    output: Receiver<ConsensusTransaction>,
+    ordered: Vec<ConsensusTransaction>,
+}
+
+// simulated time of the first tick, and length of a tick, in microseconds
+pub const SIM_EPOCH: u64 = 1_500_000_000_000_000;
+pub const TICK_MICROS: u64 = 10_000;
+
+// Runs `nodes` hashgraphs in one thread, gossiping through a simulated
+// network. Every choice (who gossips with whom, delays, faults, submitted
+// transactions) and every key comes from the seeded generator, and the
+// events are timestamped by the tick clock, so a failing seed replays the
+// same schedule.
+pub struct Simulator {
+    pub config: SimConfig,
+    rng: XorShiftRng,
+    nodes: Vec<SimNode>,
+    // (delivery tick, sequence) -> message, delivered in that order
+    in_flight: BTreeMap<(u64, u64), Envelope>,
+    seq: u64,
+    tick: u64,
+    txs: u64,
+}
+
+impl Simulator {
+    pub fn new(config: SimConfig) -> Simulator {
+        let mut rng = XorShiftRng::from_seed([config.seed, 0x193a_6754, 0xa8a7_d469, 0x9783_0e05]);
+
+        let keys = (0..config.nodes)
+            .map(|_| {
+                let mut seed = [0; 32];
+
+                rng.fill_bytes(&mut seed);
+
+                Key::from_seed(&seed).unwrap()
+            })
+            .collect::<Vec<Key>>();
+
+        let mut peers = Peers::new();
+
+        let ids = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let addr = format!("127.0.0.1:{}", 1000 + i).parse().unwrap();
+                let peer = Peer::new(addr, key.get_pub());
+
+                peers.add(peer.clone());
+
+                peer.id
+            })
+            .collect::<Vec<u64>>();
+
+        let nodes = keys
+            .into_iter()
+            .zip(ids)
+            .enumerate()
+            .map(|(i, (key, id))| {
+                let (tx_out, output) = channel();
+
+                let mut hg = Hashgraph::new(key.clone(), Arc::new(Mutex::new(tx_out)));
+
+                let behaviour = config
+                    .byzantine
+                    .iter()
+                    .find(|(node, _)| *node == i)
+                    .map(|(_, behaviour)| behaviour.clone());
+
+                hg.clock = Some(SIM_EPOCH);
+
+                if let Some(Byzantine::LieTimestamp(skew)) = behaviour {
+                    hg.clock_skew = skew;
+                }
+
+                let mut node_peers = peers.clone();
+
+                node_peers.self_id = id;
+
+                hg.bootstrap(node_peers);
+
+                assert!(hg.add_root_event(vec![]));
+
+                SimNode {
+                    hg,
+                    key,
+                    behaviour,
+                    output,
+                    ordered: vec![],
+                }
+            })
+            .collect();
+
+        Simulator {
+            rng,
+            config,
+            nodes,
+            in_flight: BTreeMap::new(),
+            seq: 0,
+            tick: 0,
+            txs: 0,
+        }
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        let now = self.tick;
+
+        let time = self.now();
+
+        for node in self.nodes.iter_mut() {
+            node.hg.clock = Some(time);
+        }
+
+        let due = self
+            .in_flight
+            .keys()
+            .take_while(|&&(at, _)| at <= now)
+            .cloned()
+            .collect::<Vec<(u64, u64)>>();
+
+        for key in due {
+            let envelope = self.in_flight.remove(&key).unwrap();
+
+            self.deliver(envelope);
+        }
+
+        for _ in 0..self.config.gossips_per_tick {
+            let from = self.rng.gen_range(0, self.nodes.len());
+            let to = self.rng.gen_range(0, self.nodes.len() - 1);
+            let to = if to >= from { to + 1 } else { to };
+
+            let known = self.nodes[from].hg.events.known_events();
+
+            self.send(from, to, Message::Pull(known));
+        }
+
+        if self.rng.gen::<f64>() < self.config.tx_rate {
+            let node = self.rng.gen_range(0, self.nodes.len());
+
+            let tx = format!("tx{}", self.txs).into_bytes();
+
+            self.txs += 1;
+
+            self.nodes[node].hg.submit_tx(tx);
+        }
+
+        for node in self.nodes.iter_mut() {
+            node.ordered.extend(node.output.try_iter());
+        }
+
+        self.tick += 1;
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: Message) {
+        if !self.reachable(from, to) || self.rng.gen::<f64>() < self.config.drop_rate {
+            return;
+        }
+
+        let message = match message {
+            Message::Pull(known) => Message::Pull(known),
+            Message::PullResponse(diff) => match self.tamper(from, to, diff) {
+                Some(diff) => Message::PullResponse(diff),
+                None => return,
+            },
+            Message::Push(diff) => match self.tamper(from, to, diff) {
+                Some(diff) => Message::Push(diff),
+                None => return,
+            },
+        };
+
+        let copies = if self.rng.gen::<f64>() < self.config.duplicate_rate {
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut delay = self
+                .rng
+                .gen_range(self.config.min_delay, self.config.max_delay + 1);
+
+            if self.rng.gen::<f64>() < self.config.reorder_rate {
+                delay += self.config.max_delay;
+            }
+
+            self.seq += 1;
+
+            self.in_flight.insert(
+                (self.tick + delay, self.seq),
+                Envelope {
+                    from,
+                    to,
+                    message: message.clone(),
+                },
+            );
+        }
+    }
+
+    fn deliver(&mut self, envelope: Envelope) {
+        // a partition also cuts the messages already sent
+        if !self.reachable(envelope.from, envelope.to) {
+            return;
+        }
+
+        let from_id = self.id_of(envelope.from);
+        let to_id = self.id_of(envelope.to);
+
+        match envelope.message {
+            Message::Pull(known) => {
+                let diff =
+                    self.nodes[envelope.to]
+                        .hg
+                        .get_events_diff(0, known, self.config.page_size);
+
+                self.send(envelope.to, envelope.from, Message::PullResponse(diff));
+            }
+            Message::PullResponse(diff) => {
+                let page_size = self.config.page_size;
+
+                let replies = {
+                    let hg = &mut self.nodes[envelope.to].hg;
+
+                    if hg.check_events_diff(&diff).is_err() {
+                        vec![]
+                    } else if diff.has_more {
+                        // asks for the next page, unless this one was useless
+                        if hg.merge_page(&diff) > 0 {
+                            vec![Message::Pull(hg.events.known_events())]
+                        } else {
+                            vec![]
+                        }
+                    } else {
+                        let known = diff.known.clone();
+
+                        match hg.merge_events(to_id, from_id, diff) {
+                            Ok(_) => Simulator::push_pages(hg, from_id, known, page_size),
+                            Err(_) => vec![],
+                        }
+                    }
+                };
+
+                for reply in replies {
+                    self.send(envelope.to, envelope.from, reply);
+                }
+            }
+            Message::Push(diff) => {
+                let hg = &mut self.nodes[envelope.to].hg;
+
+                if hg.check_events_diff(&diff).is_err() {
+                    return;
+                }
+
+                // the last page makes the sync event
+                if diff.has_more {
+                    hg.merge_page(&diff);
+                } else {
+                    let _ = hg.merge_events(to_id, from_id, diff);
+                }
+            }
+        }
+    }
+
+    // the push answering a pull, in pages as a node sends them. The first one
+    // goes even empty, for the peer to make its sync event
+    fn push_pages(
+        hg: &Hashgraph,
+        recipient_id: EventCreator,
+        known: HashMap<EventCreator, u64>,
+        page_size: u64,
+    ) -> Vec<Message> {
+        let mut known = known;
+        let mut pages = vec![];
+
+        loop {
+            let page = hg.get_events_diff(recipient_id, known.clone(), page_size);
+
+            if page.diff.is_empty() && !pages.is_empty() {
+                break;
+            }
+
+            known = page.known_after(known);
+
+            let has_more = page.has_more;
+
+            pages.push(Message::Push(page));
+
+            if !has_more {
+                break;
+            }
+        }
+
+        pages
+    }
+
+    // the diff a byzantine node sends instead, None to send nothing
+    fn tamper(&mut self, from: usize, to: usize, diff: EventsDiff) -> Option<EventsDiff> {
+        let behaviour = match self.nodes[from].behaviour.clone() {
+            Some(behaviour) => behaviour,
+            None => return Some(diff),
+        };
+
+        let creator = self.id_of(from);
+
+        let last = self.nodes[from]
+            .hg
+            .events
+            .get_last_event_of(creator)
+            .unwrap();
+
+        // on the tick clock like honest events, for the run to replay
+        let timestamp = (self.now() as i64 + self.nodes[from].hg.clock_skew) as u64;
+
+        let stamp = |mut event: Event| {
+            event.timestamp = timestamp;
+            event.hash = event.calc_hash();
+
+            event
+        };
+
+        let extras = match behaviour {
+            Byzantine::Withhold(ref victims) if victims.contains(&to) => return None,
+            Byzantine::Withhold(_) | Byzantine::LieTimestamp(_) => return Some(diff),
+            Byzantine::ForgeOtherParent => {
+                let mut forged = [0; 32];
+
+                self.rng.fill_bytes(&mut forged);
+
+                vec![stamp(Event::new(
+                    last.id + 1,
+                    creator,
+                    last.hash,
+                    forged,
+                    vec![],
+                    vec![],
+                ))]
+            }
+            Byzantine::Fork => vec![stamp(Event::new(
+                last.id,
+                creator,
+                last.self_parent,
+                last.other_parent,
+                vec![b"fork".to_vec()],
+                vec![],
+            ))],
+            Byzantine::Oversized(bytes) => vec![stamp(Event::new(
+                last.id + 1,
+                creator,
+                last.hash,
+                EMPTY_HASH,
+                vec![vec![0; bytes]],
+                vec![],
+            ))],
+            Byzantine::Flood(count) => {
+                let mut parent = last.clone();
+
+                (0..count)
+                    .map(|_| {
+                        let event = stamp(Event::new(
+                            parent.id + 1,
+                            creator,
+                            parent.hash,
+                            EMPTY_HASH,
+                            vec![b"flood".to_vec()],
+                            vec![],
+                        ));
+
+                        parent = event.clone();
+
+                        event
+                    })
+                    .collect()
+            }
+        };
+
+        let node = &self.nodes[from];
+
+        let mut diff = diff;
+
+        for mut extra in extras {
+            extra.sign(&node.key);
+
+            diff.diff
+                .entry(creator)
+                .or_insert_with(BTreeMap::new)
+                .insert(extra.id, extra);
+        }
+
+        diff.sign(&node.key);
+
+        Some(diff)
+    }
+
+    fn reachable(&self, a: usize, b: usize) -> bool {
+        let now = self.tick;
+
+        self.config
+            .partitions
+            .iter()
+            .filter(|partition| partition.from <= now && now < partition.to)
+            .all(|partition| {
+                let group_of =
+                    |node: usize| partition.groups.iter().position(|g| g.contains(&node));
+
+                group_of(a) == group_of(b)
+            })
+    }
+
+    fn id_of(&self, node: usize) -> u64 {
+        self.nodes[node].hg.get_last_decided_peers().self_id
+    }
+
+    // simulated time of the current tick, in microseconds
+    pub fn now(&self) -> u64 {
+        SIM_EPOCH + self.tick * TICK_MICROS
+    }
+
+    pub fn ordered(&self, node: usize) -> &[ConsensusTransaction] {
+        &self.nodes[node].ordered
+    }
+
+    pub fn hashgraph(&self, node: usize) -> &Hashgraph {
+        &self.nodes[node].hg
+    }
+
+    pub fn honest(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].behaviour.is_none())
+            .collect()
+    }
+
+    pub fn submitted(&self) -> u64 {
+        self.txs
+    }
+
+    // every honest ordered stream must be a prefix of the longest one
+    pub fn assert_prefix_consistent(&self) {
+        let honest = self.honest();
+
+        let longest = honest
+            .iter()
+            .map(|&i| &self.nodes[i].ordered)
+            .max_by_key(|ordered| ordered.len())
+            .unwrap();
+
+        for &i in honest.iter() {
+            let node = &self.nodes[i];
+
+            assert!(
+                longest.starts_with(&node.ordered),
+                "Seed {}: node {} diverged after {} ordered transactions",
+                self.config.seed,
+                i,
+                node.ordered
+                    .iter()
+                    .zip(longest.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use bincode;
+
+use super::event::{Event, EventCreator, EventHash};
+use super::fork::ForkProof;
+use super::round::Round;
+
+const SNAPSHOT_MAGIC: &[u8] = b"HGSNAP";
+
+// bumped on any change of the serialized structures
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// Whole consensus state of a node, enough to resume it on a fresh one
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub events: Vec<Event>, // each creator chain in order
+    pub undecided: Vec<EventHash>,
+    pub rounds: BTreeMap<u64, Round>,
+    pub forks: HashMap<EventCreator, ForkProof>,
+    pub fork_views: HashMap<EventHash, Vec<EventCreator>>,
+    pub consensus_index: u64,
+    pub last_committed_round: u64,
+    pub state_hash: Vec<u8>,
+    pub app_state: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    // File format: magic | version (u32 LE) | bincode payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+
+        bytes.extend(bincode::serialize(&SNAPSHOT_VERSION).unwrap());
+        bytes.extend(bincode::serialize(self).unwrap());
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, String> {
+        let header_len = SNAPSHOT_MAGIC.len() + 4;
+
+        if bytes.len() < header_len || !bytes.starts_with(SNAPSHOT_MAGIC) {
+            return Err("Not a snapshot".to_string());
+        }
+
+        let version: u32 = bincode::deserialize(&bytes[SNAPSHOT_MAGIC.len()..header_len])
+            .map_err(|e| format!("Bad snapshot version: {}", e))?;
+
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "Unsupported snapshot version {} (expected {})",
+                version, SNAPSHOT_VERSION
+            ));
+        }
+
+        bincode::deserialize(&bytes[header_len..]).map_err(|e| format!("Bad snapshot: {}", e))
+    }
+
+    // written aside then renamed, so an existing snapshot is never half replaced
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let tmp_path = path.with_extension("tmp");
+
+        File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(&self.to_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("Cannot write snapshot {:?}: {}", path, e))
+    }
+
+    pub fn load(path: &Path) -> Result<Snapshot, String> {
+        let mut content = vec![];
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut content))
+            .map_err(|e| format!("Cannot read snapshot {:?}: {}", path, e))?;
+
+        Self::from_bytes(&content).map_err(|e| format!("{:?}: {}", path, e))
+    }
+}
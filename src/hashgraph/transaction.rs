@@ -0,0 +1,12 @@
+use super::event::{EventCreator, EventHash};
+
+// A transaction as it comes out of consensus
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ConsensusTransaction {
+    pub index: u64, // position in the consensus order, starting at 0
+    pub data: Vec<u8>,
+    pub event_hash: EventHash,
+    pub creator: EventCreator,
+    pub round_received: u64,
+    pub timestamp: u64, // consensus timestamp of the event
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use event::EventCreator;
+use event_store::EventStore;
+use events::{EventsDiff, Frame, TxRef};
+use hashgraph::{Hashgraph, PULL_PAGE_SIZE};
+use internal_txs::PeerTx;
+use metrics;
+use node::Node;
+use peer::Peer;
+use peers::Peers;
+use rpc::HgRpc;
+use tx_cache::ServedTxs;
+
+// The calls a node makes to one of its peers
+pub trait Connection: Send {
+    fn ask_join(&mut self, peer: Peer) -> Result<bool, String>;
+    fn fast_sync(&mut self, peer_id: u64) -> Result<Frame, String>;
+    fn pull(&mut self, known: HashMap<EventCreator, u64>) -> Result<EventsDiff, String>;
+    fn pull_compact(&mut self, known: HashMap<EventCreator, u64>) -> Result<EventsDiff, String>;
+    fn fetch_txs(&mut self, tx_refs: Vec<TxRef>) -> Result<Vec<(TxRef, Vec<u8>)>, String>;
+    fn push(&mut self, events: EventsDiff) -> Result<bool, String>;
+    fn close(&mut self);
+}
+
+// How nodes reach each other. A node serves its handler on its listen
+// address and connects to the others by their address.
+pub trait Transport: Send + Sync {
+    fn listen(&self, addr: SocketAddr, handler: Handler) -> Result<(), String>;
+    fn connect(&self, addr: SocketAddr) -> Result<Box<Connection>, String>;
+    fn close(&self, addr: SocketAddr);
+}
+
+// What a node answers to its peers, whatever the transport
+#[derive(Clone, Default)]
+pub struct Handler {
+    pub node: Arc<RwLock<Node>>,
+    pub hg: Arc<RwLock<Hashgraph<Box<EventStore>>>>,
+    pub peers: Arc<RwLock<Peers>>,
+    pub served: Arc<Mutex<ServedTxs>>,
+    // counter of the last push taken from each peer
+    pub pushed: Arc<Mutex<HashMap<EventCreator, u64>>>,
+}
+
+impl Handler {
+    pub fn fast_sync(&self, peer_id: u64) -> Frame {
+        self.hg.read().unwrap().get_last_frame(peer_id)
+    }
+
+    pub fn pull(&self, known: HashMap<EventCreator, u64>) -> EventsDiff {
+        trace!("RPC: Got events to pull {:?}", known);
+
+        self.hg
+            .read()
+            .unwrap()
+            .get_events_diff(0, known, PULL_PAGE_SIZE)
+    }
+
+    pub fn pull_compact(&self, known: HashMap<EventCreator, u64>) -> EventsDiff {
+        trace!("RPC: Got compact events to pull {:?}", known);
+
+        let events = self
+            .hg
+            .read()
+            .unwrap()
+            .get_compact_events_diff(0, known, PULL_PAGE_SIZE);
+
+        let mut served = self.served.lock().unwrap();
+
+        for tx_ref in events.lazy.iter() {
+            served.insert(tx_ref.clone());
+        }
+
+        events
+    }
+
+    pub fn fetch_txs(&self, tx_refs: Vec<TxRef>) -> Vec<(TxRef, Vec<u8>)> {
+        trace!("RPC: Got txs to fetch {:?}", tx_refs.len());
+
+        // only what we announced, a peer cannot make us read any event
+        let tx_refs = {
+            let served = self.served.lock().unwrap();
+
+            tx_refs
+                .into_iter()
+                .filter(|tx_ref| served.contains(tx_ref))
+                .collect::<Vec<TxRef>>()
+        };
+
+        self.hg.read().unwrap().get_txs(&tx_refs)
+    }
+
+    pub fn push(&self, mut events: EventsDiff) -> bool {
+        trace!(
+            "RPC: Got events to push {:?}",
+            events.diff.iter().fold(0, |c, v| c + v.1.len())
+        );
+
+        let self_id = self.hg.read().unwrap().get_last_decided_peers().self_id;
+
+        let peer = match self.hg.read().unwrap().check_events_diff(&events) {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("RPC: Rejected push: {}", e);
+
+                return false;
+            }
+        };
+
+        // signed for another node, or already taken
+        if events.recipient_id != self_id {
+            warn!("RPC: Rejected push: for {}", events.recipient_id);
+
+            return false;
+        }
+
+        {
+            let mut pushed = self.pushed.lock().unwrap();
+            let last = pushed.entry(peer.id).or_insert(0);
+
+            if events.counter <= *last {
+                warn!("RPC: Rejected push: replay from {}", peer.id);
+
+                return false;
+            }
+
+            *last = events.counter;
+        }
+
+        // a compact push, the bodies we miss come with our next pull
+        let (missing, registry) = {
+            let hg = self.hg.read().unwrap();
+
+            (hg.fill_txs(&mut events), hg.metrics.clone())
+        };
+
+        metrics::LAZY_TXS.add_with(&registry, &[("source", "missing")], missing.len() as u64);
+
+        events.drop_incomplete();
+
+        let mut hg = self.hg.write().unwrap();
+
+        // more pages follow, the last one makes our sync event
+        if events.has_more {
+            hg.merge_page(&events);
+
+            return true;
+        }
+
+        hg.merge_events(self_id, peer.id, events).is_ok()
+    }
+
+    // you are asked to add a new peer. Answer with own pub_key
+    pub fn ask_join(&self, peer: Peer) -> bool {
+        if self.hg.read().unwrap().get_last_decided_peers().len() == 1 {
+            let mut hg = self.hg.write().unwrap();
+
+            hg.add_self_event(vec![], vec![PeerTx::new_join(peer)]);
+
+            // force consensus
+            for _ in 0..6 {
+                hg.add_self_event(vec![], vec![]);
+            }
+
+            return true;
+        }
+
+        self.node.write().unwrap().peer_join(peer);
+
+        true
+    }
+}
+
+// rsrpc answers are wrapped twice, the inner layer is unwrapped as before
+macro_rules! rpc_call {
+    ($res:expr) => {
+        match $res {
+            Ok(res) => Ok(res.unwrap()),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    };
+}
+
+// The rsrpc service of rpc.rs, over TCP. Each server is owned by a thread
+// that closes it when its address is closed, or once every clone of the
+// transport is dropped.
+#[derive(Clone, Debug, Default)]
+pub struct TcpTransport {
+    servers: Arc<Mutex<HashMap<SocketAddr, Sender<()>>>>,
+}
+
+impl TcpTransport {
+    pub fn new() -> TcpTransport {
+        TcpTransport::default()
+    }
+}
+
+impl Transport for TcpTransport {
+    fn listen(&self, addr: SocketAddr, handler: Handler) -> Result<(), String> {
+        let mut servers = self.servers.lock().unwrap();
+
+        if servers.contains_key(&addr) {
+            return Err(format!("Address already in use: {}", addr));
+        }
+
+        let (stop, stopped) = channel::<()>();
+
+        servers.insert(addr, stop);
+
+        thread::spawn(move || {
+            let mut server = HgRpc::listen_tcp(&addr.to_string());
+
+            server.context.lock().unwrap().handler = handler;
+
+            // nothing is ever sent, the sender is dropped to stop
+            let _ = stopped.recv();
+
+            server.close();
+        });
+
+        Ok(())
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<Box<Connection>, String> {
+        match HgRpc::connect_tcp(&addr.to_string()) {
+            Ok(client) => Ok(Box::new(TcpConnection { client })),
+            Err(e) => Err(format!("{}", e)),
+        }
+    }
+
+    fn close(&self, addr: SocketAddr) {
+        self.servers.lock().unwrap().remove(&addr);
+    }
+}
+
+struct TcpConnection {
+    client: HgRpc::Client<::rsrpc::TcpTransport>,
+}
+
+impl Connection for TcpConnection {
+    fn ask_join(&mut self, peer: Peer) -> Result<bool, String> {
+        rpc_call!(self.client.ask_join(peer))
+    }
+
+    fn fast_sync(&mut self, peer_id: u64) -> Result<Frame, String> {
+        rpc_call!(self.client.fast_sync(peer_id))
+    }
+
+    fn pull(&mut self, known: HashMap<EventCreator, u64>) -> Result<EventsDiff, String> {
+        rpc_call!(self.client.pull(known))
+    }
+
+    fn pull_compact(&mut self, known: HashMap<EventCreator, u64>) -> Result<EventsDiff, String> {
+        rpc_call!(self.client.pull_compact(known))
+    }
+
+    fn fetch_txs(&mut self, tx_refs: Vec<TxRef>) -> Result<Vec<(TxRef, Vec<u8>)>, String> {
+        rpc_call!(self.client.fetch_txs(tx_refs))
+    }
+
+    fn push(&mut self, events: EventsDiff) -> Result<bool, String> {
+        rpc_call!(self.client.push(events))
+    }
+
+    fn close(&mut self) {
+        self.client.close();
+    }
+}
+
+enum Request {
+    AskJoin(Peer),
+    FastSync(u64),
+    Pull(HashMap<EventCreator, u64>),
+    PullCompact(HashMap<EventCreator, u64>),
+    FetchTxs(Vec<TxRef>),
+    Push(EventsDiff),
+}
+
+enum Response {
+    Bool(bool),
+    Frame(Frame),
+    EventsDiff(EventsDiff),
+    Txs(Vec<(TxRef, Vec<u8>)>),
+}
+
+type Mailbox = Sender<(Request, Sender<Response>)>;
+
+// In-process network: each listening node gets a thread answering the
+// requests sent over a channel, no port is bound. Clones share the same
+// network, give one to every node of a test.
+#[derive(Clone, Default)]
+pub struct MemTransport {
+    listeners: Arc<Mutex<HashMap<SocketAddr, (Mailbox, Arc<AtomicBool>)>>>,
+}
+
+impl MemTransport {
+    pub fn new() -> MemTransport {
+        MemTransport::default()
+    }
+}
+
+impl Transport for MemTransport {
+    fn listen(&self, addr: SocketAddr, handler: Handler) -> Result<(), String> {
+        let mut listeners = self.listeners.lock().unwrap();
+
+        if listeners.contains_key(&addr) {
+            return Err(format!("Address already in use: {}", addr));
+        }
+
+        let (mailbox, requests) = channel::<(Request, Sender<Response>)>();
+        let open = Arc::new(AtomicBool::new(true));
+
+        listeners.insert(addr, (mailbox, open.clone()));
+
+        // ends at the first request after close(), the connections left then
+        // fail like reset TCP ones
+        thread::spawn(move || {
+            for (request, respond) in requests {
+                if !open.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let response = match request {
+                    Request::AskJoin(peer) => Response::Bool(handler.ask_join(peer)),
+                    Request::FastSync(peer_id) => Response::Frame(handler.fast_sync(peer_id)),
+                    Request::Pull(known) => Response::EventsDiff(handler.pull(known)),
+                    Request::PullCompact(known) => {
+                        Response::EventsDiff(handler.pull_compact(known))
+                    }
+                    Request::FetchTxs(tx_refs) => Response::Txs(handler.fetch_txs(tx_refs)),
+                    Request::Push(events) => Response::Bool(handler.push(events)),
+                };
+
+                // the caller may have given up
+                let _ = respond.send(response);
+            }
+        });
+
+        Ok(())
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<Box<Connection>, String> {
+        match self.listeners.lock().unwrap().get(&addr) {
+            Some((mailbox, _)) => Ok(Box::new(MemConnection {
+                mailbox: Some(mailbox.clone()),
+            })),
+            None => Err(format!("Connection refused: {}", addr)),
+        }
+    }
+
+    fn close(&self, addr: SocketAddr) {
+        if let Some((_, open)) = self.listeners.lock().unwrap().remove(&addr) {
+            open.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+struct MemConnection {
+    mailbox: Option<Mailbox>,
+}
+
+impl MemConnection {
+    fn call(&mut self, request: Request) -> Result<Response, String> {
+        let mailbox = match self.mailbox {
+            Some(ref mailbox) => mailbox,
+            None => return Err("Connection closed".to_string()),
+        };
+
+        let (respond, response) = channel();
+
+        mailbox
+            .send((request, respond))
+            .map_err(|_| "Connection reset".to_string())?;
+
+        response.recv().map_err(|_| "Connection reset".to_string())
+    }
+}
+
+impl Connection for MemConnection {
+    fn ask_join(&mut self, peer: Peer) -> Result<bool, String> {
+        match self.call(Request::AskJoin(peer))? {
+            Response::Bool(res) => Ok(res),
+            _ => Err("Unexpected response".to_string()),
+        }
+    }
+
+    fn fast_sync(&mut self, peer_id: u64) -> Result<Frame, String> {
+        match self.call(Request::FastSync(peer_id))? {
+            Response::Frame(frame) => Ok(frame),
+            _ => Err("Unexpected response".to_string()),
+        }
+    }
+
+    fn pull(&mut self, known: HashMap<EventCreator, u64>) -> Result<EventsDiff, String> {
+        match self.call(Request::Pull(known))? {
+            Response::EventsDiff(events) => Ok(events),
+            _ => Err("Unexpected response".to_string()),
+        }
+    }
+
+    fn pull_compact(&mut self, known: HashMap<EventCreator, u64>) -> Result<EventsDiff, String> {
+        match self.call(Request::PullCompact(known))? {
+            Response::EventsDiff(events) => Ok(events),
+            _ => Err("Unexpected response".to_string()),
+        }
+    }
+
+    fn fetch_txs(&mut self, tx_refs: Vec<TxRef>) -> Result<Vec<(TxRef, Vec<u8>)>, String> {
+        match self.call(Request::FetchTxs(tx_refs))? {
+            Response::Txs(txs) => Ok(txs),
+            _ => Err("Unexpected response".to_string()),
+        }
+    }
+
+    fn push(&mut self, events: EventsDiff) -> Result<bool, String> {
+        match self.call(Request::Push(events))? {
+            Response::Bool(res) => Ok(res),
+            _ => Err("Unexpected response".to_string()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.mailbox = None;
+    }
+}
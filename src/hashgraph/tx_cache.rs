@@ -0,0 +1,97 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use events::TxRef;
+use receipt::{tx_id, TxId};
+
+pub const DEFAULT_TX_CACHE_BYTES: usize = 16 * 1024 * 1024;
+pub const DEFAULT_SERVED_TXS: usize = 64 * 1024;
+
+// Bodies of the large transactions seen lately, submitted here or carried by
+// an inserted event, so that a compact diff can be filled without fetching
+// them again. The oldest ones leave first.
+#[derive(Clone, Debug, Default)]
+pub struct TxCache {
+    bodies: HashMap<TxId, Vec<u8>>,
+    order: VecDeque<TxId>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl TxCache {
+    pub fn new(max_bytes: usize) -> TxCache {
+        TxCache {
+            max_bytes,
+            ..TxCache::default()
+        }
+    }
+
+    pub fn insert(&mut self, tx: &[u8]) {
+        let id = tx_id(tx);
+
+        if tx.len() > self.max_bytes || self.bodies.contains_key(&id) {
+            return;
+        }
+
+        while self.bytes + tx.len() > self.max_bytes {
+            let oldest = self.order.pop_front().unwrap();
+
+            self.bytes -= self.bodies.remove(&oldest).unwrap().len();
+        }
+
+        self.bytes += tx.len();
+        self.order.push_back(id);
+        self.bodies.insert(id, tx.to_vec());
+    }
+
+    pub fn get(&self, id: &TxId) -> Option<&Vec<u8>> {
+        self.bodies.get(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bodies.len()
+    }
+}
+
+// Refs of the compact diffs served lately, the only bodies a peer may fetch.
+// The oldest ones leave first.
+#[derive(Clone, Debug)]
+pub struct ServedTxs {
+    refs: HashSet<TxRef>,
+    order: VecDeque<TxRef>,
+    capacity: usize,
+}
+
+impl Default for ServedTxs {
+    fn default() -> ServedTxs {
+        ServedTxs::new(DEFAULT_SERVED_TXS)
+    }
+}
+
+impl ServedTxs {
+    pub fn new(capacity: usize) -> ServedTxs {
+        ServedTxs {
+            refs: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, tx_ref: TxRef) {
+        if self.capacity == 0 || self.refs.contains(&tx_ref) {
+            return;
+        }
+
+        if self.order.len() >= self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+
+            self.refs.remove(&oldest);
+        }
+
+        self.order.push_back(tx_ref.clone());
+        self.refs.insert(tx_ref);
+    }
+
+    pub fn contains(&self, tx_ref: &TxRef) -> bool {
+        self.refs.contains(tx_ref)
+    }
+}
@@ -6,19 +6,118 @@ extern crate logger;
 
 mod args;
 
+use std::path::Path;
+use std::process;
+
+use args::{Command, Config};
+use hashgraph::to_hex;
+use hashgraph::HgRpc;
 use hashgraph::Key;
 use hashgraph::Node;
 
+fn load_key(config: &Config) -> Key {
+  let passphrase = config.passphrase.as_ref().map(|s| s.as_str());
+
+  match config.key_path {
+    Some(ref path) if Path::new(path).exists() => Key::load(Path::new(path), passphrase)
+      .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+
+        process::exit(1);
+      }),
+    Some(ref path) => {
+      let key = Key::new_generate().unwrap();
+
+      if let Err(e) = key.save(Path::new(path), passphrase) {
+        eprintln!("{}", e);
+
+        process::exit(1);
+      }
+
+      key
+    }
+    None => Key::new_generate().unwrap(),
+  }
+}
+
+fn keygen(config: &Config) {
+  let key = Key::new_generate().unwrap();
+
+  if let Some(ref path) = config.key_path {
+    let passphrase = config.passphrase.as_ref().map(|s| s.as_str());
+
+    if let Err(e) = key.save(Path::new(path), passphrase) {
+      eprintln!("{}", e);
+
+      process::exit(1);
+    }
+  }
+
+  println!("Public key: {}", to_hex(&key.get_pub()));
+  println!("Peer id: {}", Key::pub_to_int(key.get_pub()));
+}
+
+fn dot(config: &Config) {
+  let addr = match config.node.connect_addr {
+    Some(addr) => addr,
+    None => {
+      eprintln!("dot: --connect is required");
+
+      process::exit(1);
+    }
+  };
+
+  let mut client = HgRpc::connect_tcp(&addr.to_string()).unwrap_or_else(|e| {
+    eprintln!("dot: Cannot connect to {}: {}", addr, e);
+
+    process::exit(1);
+  });
+
+  let res = client.get_dot();
+
+  client.close();
+
+  match res {
+    Ok(Ok(dot)) => print!("{}", dot),
+    Ok(Err(e)) => {
+      eprintln!("dot: {:?}", e);
+
+      process::exit(1);
+    }
+    Err(e) => {
+      eprintln!("dot: Cannot reach {}: {:?}", addr, e);
+
+      process::exit(1);
+    }
+  }
+}
+
 fn main() {
   let config = args::parse_config();
 
-  logger::init_logger(config.verbose);
+  logger::init_logger(config.node.verbose);
 
-  let key = Key::new_generate().unwrap();
+  match config.command {
+    Command::Keygen => return keygen(&config),
+    Command::Dot => return dot(&config),
+    Command::Run => (),
+  }
+
+  if config.node.connect_addr.is_some() && config.node.connect_key.is_none() {
+    eprintln!("--connect-key is required with --connect");
+
+    process::exit(1);
+  }
+
+  let key = load_key(&config);
+
+  let mut node = Node::new(key.clone(), config.node);
 
-  let mut node = Node::new(key.clone(), config);
+  let tx_out = node.run().unwrap_or_else(|e| {
+    eprintln!("{}", e);
 
-  let tx_out = node.run();
+    process::exit(1);
+  });
 
   node.add_tx(key.get_pub());
 